@@ -0,0 +1,260 @@
+//! Async flashing API, built on tokio.
+//!
+//! The protocol logic is shared with the blocking API (see the `connection` module), only the
+//! serial I/O is awaited instead of blocking the thread.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::{SerialPort, SerialStream};
+
+use crate::connection::Link;
+use crate::slip::{self, SlipDecoder};
+use crate::transport::MAX_RESPONSE_LEN;
+use crate::zip_file;
+use crate::{update_via, DfuError, Progress, Result, UpdateOptions, UpdateReport};
+
+/// How long [`run_async`] waits for each response, unless [`UpdateOptions::timeout`] is set.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Async transport over a serial port (or any other byte stream), using SLIP framing.
+pub struct AsyncSerialTransport<S = SerialStream> {
+    stream: S,
+    timeout: Duration,
+    buf: Vec<u8>,
+    decoder: SlipDecoder,
+}
+
+impl AsyncSerialTransport<SerialStream> {
+    /// Creates a transport that waits at most `timeout` for each response of the device.
+    pub fn new(mut port: SerialStream, timeout: Duration) -> io::Result<Self> {
+        // On Windows, this is required, otherwise communication fails with timeouts
+        // (or just hangs forever).
+        port.write_data_terminal_ready(true)?;
+
+        Ok(Self::from_stream(port, timeout))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncSerialTransport<S> {
+    /// Like [`new`](AsyncSerialTransport::new), but communicates via an arbitrary byte stream.
+    pub fn from_stream(stream: S, timeout: Duration) -> Self {
+        Self {
+            stream,
+            timeout,
+            buf: Vec::new(),
+            decoder: SlipDecoder::new(MAX_RESPONSE_LEN),
+        }
+    }
+
+    /// Sends a single request frame to the device.
+    pub async fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        // Go through an intermediate buffer to avoid writing every byte individually.
+        self.buf.clear();
+        slip::encode_frame(frame, &mut self.buf)?;
+        self.stream.write_all(&self.buf).await?;
+        self.stream.flush().await
+    }
+
+    /// Waits for a single response frame from the device and appends it to `buf`.
+    pub async fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        let stream = &mut self.stream;
+        let decoder = &mut self.decoder;
+        let recv = async {
+            let mut chunk = [0; 64];
+            loop {
+                if let Some(frame) = decoder.next_frame() {
+                    buf.extend_from_slice(&frame);
+                    return Ok(());
+                }
+
+                let n = stream.read(&mut chunk).await?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                decoder.push(&chunk[..n])?;
+            }
+        };
+        tokio::time::timeout(self.timeout, recv)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+    }
+
+    /// Returns the largest request frame (including the opcode) that can be sent, given the
+    /// `mtu` reported by the device.
+    pub fn max_frame_size(&self, mtu: u16) -> usize {
+        // The MTU applies to the SLIP-encoded frame.
+        slip::max_frame_len(usize::from(mtu))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Link for AsyncSerialTransport<S> {
+    async fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        AsyncSerialTransport::send_frame(self, frame).await
+    }
+
+    async fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        AsyncSerialTransport::recv_frame(self, buf).await
+    }
+
+    fn max_frame_size(&self, mtu: u16) -> usize {
+        AsyncSerialTransport::max_frame_size(self, mtu)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Some(self.timeout)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+/// Async version of [`run_with_progress`](crate::run_with_progress).
+///
+/// Only the communication with the device is asynchronous. The DFU package is still read with
+/// blocking file I/O, which is fast compared to the transfer, and only happens in small pieces
+/// while flashing.
+///
+/// Each response is awaited for at most [`UpdateOptions::timeout`], or 60 seconds if it is not set.
+pub async fn run_async(
+    port: SerialStream,
+    zip_path: &str,
+    options: &UpdateOptions,
+    mut progress: impl FnMut(Progress) + Send,
+) -> Result<UpdateReport> {
+    let mut package =
+        zip_file::read_zip_file(zip_path, options.strict_dfu_version, &options.manifest_name)?;
+    let timeout = options.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let transport = AsyncSerialTransport::new(port, timeout).map_err(DfuError::Serial)?;
+    update_via(transport, &mut package, options, &mut progress).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::init_packet::build_init_packet;
+    use crate::zip_file::DfuPackage;
+
+    /// Plays the part of a bootloader with a maximum object size of 8 bytes, returning the
+    /// firmware it received once the connection is closed.
+    async fn fake_device(stream: DuplexStream) -> Vec<u8> {
+        let mut transport = AsyncSerialTransport::from_stream(stream, Duration::from_secs(5));
+        let mut firmware = Vec::new();
+        let mut command = Vec::new();
+        let mut object = Vec::new();
+        let mut is_data = false;
+        let mut frame = Vec::new();
+        loop {
+            frame.clear();
+            match transport.recv_frame(&mut frame).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return firmware,
+                Err(e) => panic!("{}", e),
+            }
+
+            let payload = match frame[0] {
+                0x00 => vec![1],
+                0x07 => 64u16.to_le_bytes().to_vec(),
+                0x09 => vec![frame[1]],
+                0x0A => [0x52840u32, 0, 1024 * 1024, 256 * 1024, 4096]
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect(),
+                0x06 => [8u32, 0, 0]
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect(),
+                0x01 => {
+                    is_data = frame[1] == 0x02;
+                    object.clear();
+                    vec![]
+                }
+                0x08 => {
+                    object.extend_from_slice(&frame[1..]);
+                    continue;
+                }
+                0x03 => {
+                    let mut received = if is_data {
+                        firmware.clone()
+                    } else {
+                        command.clone()
+                    };
+                    received.extend_from_slice(&object);
+                    let mut payload = (received.len() as u32).to_le_bytes().to_vec();
+                    payload.extend_from_slice(&crc32fast::hash(&received).to_le_bytes());
+                    payload
+                }
+                0x04 => {
+                    if is_data {
+                        firmware.append(&mut object);
+                    } else {
+                        command.append(&mut object);
+                    }
+                    vec![]
+                }
+                0x02 => vec![],
+                opcode => panic!("unexpected opcode 0x{:02x}", opcode),
+            };
+
+            let mut response = vec![0x60, frame[0], 0x01];
+            response.extend_from_slice(&payload);
+            transport.send_frame(&response).await.unwrap();
+        }
+    }
+
+    fn package(bin: &[u8]) -> DfuPackage<Cursor<Vec<u8>>> {
+        let zip = zip_file::zip_package(&[
+            (
+                "manifest.json",
+                br#"{"manifest":{"application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#,
+            ),
+            ("app.dat", &build_init_packet(bin)),
+            ("app.bin", bin),
+        ]);
+        zip_file::read_zip(zip, false, "manifest.json").unwrap()
+    }
+
+    /// Fails to compile if the future returned by `run_async` can't be spawned on a
+    /// multi-threaded runtime.
+    #[allow(dead_code)]
+    fn run_async_is_send(port: SerialStream, options: &UpdateOptions) {
+        fn assert_send<T: Send>(_: T) {}
+        assert_send(run_async(port, "package.zip", options, |_| {}));
+    }
+
+    #[tokio::test]
+    async fn update() {
+        let bin = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let mut package = package(&bin);
+        let (host, device) = tokio::io::duplex(1024);
+        let transport = AsyncSerialTransport::from_stream(host, Duration::from_secs(5));
+
+        let options = UpdateOptions::default();
+        let mut progress = Vec::new();
+        let mut record_progress = |p: Progress| progress.push(p.sent);
+        let (report, firmware) = tokio::join!(
+            update_via(transport, &mut package, &options, &mut record_progress),
+            fake_device(device),
+        );
+
+        let report = report.unwrap();
+        assert_eq!(firmware, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0xff, 0xff]);
+        assert_eq!(report.chunks, 2);
+        assert_eq!(report.final_crc, crc32fast::hash(&firmware));
+        assert_eq!(progress, vec![0, 8, 12]);
+        assert_eq!(report.images.len(), 1);
+        assert_eq!(report.images[0].padded_len, 12);
+        assert_eq!(report.images[0].bin_crc, crc32fast::hash(&bin));
+    }
+}
@@ -0,0 +1,1299 @@
+use log::LevelFilter;
+use serialport::{available_ports, SerialPort, SerialPortType, UsbPortInfo};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Nordic's vendor ID. Nordic's default nRF52 bootloader supplies this vendor ID. If the device has
+/// a custom bootloader that supplies a different VID, it can be selected with `--vid`.
+///
+/// See https://usb.org/members which lists Nordic Semiconductor's decimal ID as 6421.
+const NORDIC_BOOTLOADER_USB_VID: u16 = 0x1915;
+
+/// The product ID supplied by Nordic's default nRF52 bootloader. If the device has a custom
+/// bootloader that supplies a different PID, it can be selected with `--pid`.
+const NORDIC_BOOTLOADER_USB_PID: u16 = 0x521f;
+
+/// Default serial baud rate, used unless `--baud` is passed.
+const DEFAULT_BAUD_RATE: u32 = 115200;
+
+/// Default serial read/write timeout, used unless `--timeout-ms` is passed.
+const DEFAULT_TIMEOUT_MS: u64 = 60000;
+
+/// How long to wait for the bootloader to show up after triggering DFU mode.
+const TRIGGER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time `--wait-reboot` waits for the device to reboot, used unless
+/// `--wait-reboot-timeout-ms` is passed.
+const DEFAULT_REBOOT_TIMEOUT_MS: u64 = 10000;
+
+/// Default delay between attempts of `--update-retries`, used unless `--update-retry-delay-ms` is
+/// passed.
+const DEFAULT_UPDATE_RETRY_DELAY_MS: u64 = 1000;
+
+/// How long to wait for the bootloader's port to disappear when the device resets between two
+/// packages. Devices behind a USB-to-serial bridge keep their port, so this is not an error.
+const RESET_DETECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the serial ports are listed while waiting for a device to (dis)appear.
+const PORT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What to do about being denied access to a serial port.
+#[cfg(target_os = "linux")]
+const PERMISSION_HINT: &str =
+    "add your user to the `dialout` group (`uucp` on some distributions) \
+    and log in again, or run with appropriate permissions";
+#[cfg(not(target_os = "linux"))]
+const PERMISSION_HINT: &str =
+    "make sure no other program is using the port, or run with appropriate permissions";
+
+/// Exit code used when `--list` finds no matching devices.
+const EXIT_NO_DEVICES: i32 = 2;
+
+/// Exit code used when `--verify` finds that the device does not hold the firmware.
+const EXIT_MISMATCH: i32 = 3;
+
+/// Exit code used when the update is cancelled with Ctrl-C (128 + SIGINT, like shells do).
+const EXIT_CANCELLED: i32 = 130;
+
+fn main() {
+    let mut args = pico_args::Arguments::from_env();
+    let json = args.contains("--json");
+
+    match main_body(args, json) {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "status": "error", "message": e.to_string() })
+                );
+            } else {
+                eprintln!("error: {}", e);
+            }
+            std::process::exit(if is_cancelled(&*e) { EXIT_CANCELLED } else { 1 });
+        }
+    }
+}
+
+/// Runs the tool with the command line `args`, returning the process exit code.
+///
+/// If `json` is set, the result of flashing is printed to stdout as JSON.
+fn main_body(mut args: pico_args::Arguments, json: bool) -> Result<i32> {
+    // We show info and higher levels by default, but allow overriding this via `--log-level`, or
+    // `RUST_LOG` which takes precedence.
+    let log_level = args
+        .opt_value_from_fn("--log-level", parse_log_level)?
+        .unwrap_or(LevelFilter::Info);
+    env_logger::builder()
+        .filter_level(log_level)
+        .parse_default_env()
+        .init();
+
+    let vid = args
+        .opt_value_from_fn("--vid", parse_u16)?
+        .unwrap_or(NORDIC_BOOTLOADER_USB_VID);
+    let pid = args
+        .opt_value_from_fn("--pid", parse_u16)?
+        .unwrap_or(NORDIC_BOOTLOADER_USB_PID);
+    let baud_rate = args
+        .opt_value_from_str("--baud")?
+        .unwrap_or(DEFAULT_BAUD_RATE);
+    let timeout = Duration::from_millis(
+        args.opt_value_from_str("--timeout-ms")?
+            .unwrap_or(DEFAULT_TIMEOUT_MS),
+    );
+    let activation_timeout = args
+        .opt_value_from_str("--activation-timeout-ms")?
+        .map(Duration::from_millis);
+    let require_activation_response = args.contains("--require-activation-response");
+    let prn = args.opt_value_from_str("--prn")?.unwrap_or(0);
+    let resume = !args.contains("--no-resume");
+    let chunk_retries = args.opt_value_from_str("--retries")?.unwrap_or(3);
+    let update_retries: u32 = args.opt_value_from_str("--update-retries")?.unwrap_or(0);
+    let update_retry_delay = Duration::from_millis(
+        args.opt_value_from_str("--update-retry-delay-ms")?
+            .unwrap_or(DEFAULT_UPDATE_RETRY_DELAY_MS),
+    );
+    let mtu_fallback_after = args
+        .opt_value_from_str("--mtu-fallback-after")?
+        .unwrap_or(0);
+    let max_mtu: Option<u16> = args.opt_value_from_str("--max-mtu")?;
+    let pad = !args.contains("--no-pad");
+    let packet_delay =
+        Duration::from_micros(args.opt_value_from_str("--packet-delay-us")?.unwrap_or(0));
+    let expected_part = args.opt_value_from_fn("--expect-hw", parse_part)?;
+    let port_name: Option<String> = args.opt_value_from_str("--port")?;
+    let tcp: Option<String> = args.opt_value_from_str("--tcp")?;
+    let serial_number: Option<String> = args.opt_value_from_str("--serial")?;
+    let dry_run = args.contains("--dry-run");
+    let strict_dfu_version = args.contains("--strict-dfu-version");
+    let ignore_sd_req = args.contains("--ignore-sd-req");
+    let manifest_name: Option<String> = args.opt_value_from_str("--manifest")?;
+    let expected_sha256 = args.opt_value_from_fn("--sha256", parse_sha256)?;
+    let skip_if_current = args.contains("--skip-if-current");
+    let reset_first = args.contains("--reset-first");
+    let only: Vec<nrfdfu::ImageKind> = args.values_from_fn("--only", parse_image_kind)?;
+    let accepted_protocol_versions: Vec<u8> = args.values_from_str("--allow-protocol-version")?;
+    let force_protocol_version = args.contains("--force");
+    let trigger_vid = args.opt_value_from_fn("--trigger-vid", parse_u16)?;
+    let trigger_pid = args.opt_value_from_fn("--trigger-pid", parse_u16)?;
+    #[cfg(not(feature = "trigger"))]
+    if trigger_vid.is_some() || trigger_pid.is_some() {
+        return Err(
+            "`--trigger-vid` and `--trigger-pid` require nrfdfu to be built with the \
+             `trigger` feature"
+                .into(),
+        );
+    }
+    let dat_path: Option<String> = args.opt_value_from_str("--dat")?;
+    let bin_path: Option<String> = args.opt_value_from_str("--bin")?;
+    let all = args.contains("--all");
+    let verify = args.contains("--verify");
+    let progress = args.contains("--progress");
+    #[cfg(not(feature = "indicatif"))]
+    if progress {
+        return Err("`--progress` requires nrfdfu to be built with the `indicatif` feature".into());
+    }
+    let continue_on_error = args.contains("--continue-on-error");
+    let wait_reboot = args.contains("--wait-reboot");
+    let reboot_timeout = Duration::from_millis(
+        args.opt_value_from_str("--wait-reboot-timeout-ms")?
+            .unwrap_or(DEFAULT_REBOOT_TIMEOUT_MS),
+    );
+
+    if args.contains("--list") {
+        check_no_remaining_args(args)?;
+        return list_ports(vid, pid);
+    }
+
+    if args.contains("--info") {
+        check_no_remaining_args(args)?;
+        let transport: Box<dyn nrfdfu::Transport> = match &tcp {
+            Some(_) if port_name.is_some() || serial_number.is_some() => {
+                return Err("`--tcp` cannot be combined with `--port` or `--serial`".into());
+            }
+            Some(addr) => Box::new(connect_tcp(addr, timeout)?),
+            None => {
+                let port = select_port(
+                    port_name.as_deref(),
+                    vid,
+                    pid,
+                    serial_number.as_deref(),
+                    baud_rate,
+                    timeout,
+                )?;
+                Box::new(nrfdfu::SerialTransport::new(port.port)?)
+            }
+        };
+        let options = nrfdfu::UpdateOptions {
+            accepted_protocol_versions,
+            force_protocol_version,
+            ..Default::default()
+        };
+        return print_info(transport, &options);
+    }
+
+    let firmwares = match (dat_path, bin_path) {
+        (Some(dat), Some(bin)) => {
+            check_no_remaining_args(args)?;
+            vec![Firmware::Files { dat, bin }]
+        }
+        (None, None) => {
+            let zip_paths = free_args(args)?;
+            if zip_paths.is_empty() {
+                return Err("missing argument (expected path to .zip file)".into());
+            }
+            zip_paths.into_iter().map(Firmware::Package).collect()
+        }
+        _ => return Err("`--dat` and `--bin` must be used together".into()),
+    };
+
+    let mut options = nrfdfu::UpdateOptions {
+        strict_dfu_version,
+        accepted_protocol_versions: accepted_protocol_versions.clone(),
+        force_protocol_version,
+        only: only.clone(),
+        pad,
+        ..Default::default()
+    };
+    if let Some(name) = &manifest_name {
+        options.manifest_name = name.clone();
+    }
+    let firmwares = match expected_sha256 {
+        Some(expected) => {
+            let path = match firmwares.as_slice() {
+                [Firmware::Package(zip_path)] => zip_path,
+                [Firmware::Package(_), ..] => {
+                    return Err("`--sha256` can only be used with a single package".into())
+                }
+                _ => return Err("`--sha256` can only be used with a DFU package".into()),
+            };
+            vec![check_sha256(path, &expected)?]
+        }
+        None => firmwares,
+    };
+    if dry_run {
+        for firmware in &firmwares {
+            check_package(firmware, &options)?;
+        }
+        return Ok(0);
+    }
+
+    if firmwares.len() > 1 && (verify || all || tcp.is_some()) {
+        return Err(
+            "`--verify`, `--all` and `--tcp` can only be used with a single package".into(),
+        );
+    }
+    let firmware = &firmwares[0];
+
+    if verify {
+        let port = select_port(
+            port_name.as_deref(),
+            vid,
+            pid,
+            serial_number.as_deref(),
+            baud_rate,
+            timeout,
+        )?;
+        return verify_device(port, firmware, &options);
+    }
+
+    // On Ctrl-C, abort the transfer instead of leaving a half-sent object on the device.
+    let cancel = Arc::new(AtomicBool::new(false));
+    {
+        let cancel = cancel.clone();
+        ctrlc::set_handler(move || {
+            if cancel.swap(true, Ordering::SeqCst) {
+                std::process::exit(EXIT_CANCELLED);
+            }
+            log::warn!("Cancelling the update, press Ctrl-C again to exit immediately");
+        })?;
+    }
+
+    let new_update = |only: &[nrfdfu::ImageKind]| {
+        let mut update = nrfdfu::DfuUpdate::builder()
+            .prn(prn)
+            .resume(resume)
+            .chunk_retries(chunk_retries)
+            .mtu_fallback_after(mtu_fallback_after)
+            .pad(pad)
+            .inter_packet_delay(packet_delay)
+            .require_activation_response(require_activation_response)
+            .strict_dfu_version(strict_dfu_version)
+            .ignore_sd_req(ignore_sd_req)
+            .skip_if_current(skip_if_current)
+            .reset_first(reset_first)
+            .force_protocol_version(force_protocol_version)
+            .cancel_flag(cancel.clone());
+        for &version in &accepted_protocol_versions {
+            update = update.accept_protocol_version(version);
+        }
+        for &kind in only {
+            update = update.only(kind);
+        }
+        if let Some(part) = expected_part {
+            update = update.expected_hw(part);
+        }
+        if let Some(max_mtu) = max_mtu {
+            update = update.max_mtu(max_mtu);
+        }
+        if let Some(timeout) = activation_timeout {
+            update = update.activation_timeout(timeout);
+        }
+        if let Some(name) = &manifest_name {
+            update = update.manifest_name(name.as_str());
+        }
+        #[cfg(feature = "indicatif")]
+        if progress {
+            update = update.progress_callback(progress_bar());
+        }
+        update
+    };
+
+    if let Some(addr) = &tcp {
+        if verify
+            || all
+            || port_name.is_some()
+            || serial_number.is_some()
+            || trigger_vid.is_some()
+            || wait_reboot
+        {
+            return Err(
+                "`--tcp` cannot be combined with `--verify`, `--all`, `--port`, \
+                `--serial`, `--trigger-vid` or `--wait-reboot`"
+                    .into(),
+            );
+        }
+        flash_tcp(addr, firmware, new_update(&only), timeout, json)?;
+        return Ok(0);
+    }
+
+    if all {
+        if port_name.is_some() || serial_number.is_some() || trigger_vid.is_some() || wait_reboot {
+            return Err(
+                "`--all` cannot be combined with `--port`, `--serial`, `--trigger-vid` \
+                 or `--wait-reboot`"
+                    .to_string()
+                    .into(),
+            );
+        }
+        return flash_all(
+            vid,
+            pid,
+            baud_rate,
+            timeout,
+            firmware,
+            || new_update(&only),
+            json,
+        );
+    }
+
+    match (trigger_vid, trigger_pid) {
+        #[cfg(feature = "trigger")]
+        (Some(trigger_vid), Some(trigger_pid)) => {
+            log::info!("Resetting device into bootloader...");
+            nrfdfu::trigger_dfu(trigger_vid, trigger_pid, serial_number.as_deref())?;
+            wait_for_port(vid, pid, serial_number.as_deref())?;
+        }
+        (None, None) => {}
+        _ => return Err("`--trigger-vid` and `--trigger-pid` must be used together".into()),
+    }
+
+    let reboot = RebootWait {
+        vid,
+        pid,
+        app: trigger_vid.zip(trigger_pid),
+        serial_number: serial_number.as_deref(),
+        timeout: reboot_timeout,
+    };
+    let mut results = Vec::new();
+    // Port that the previous package was flashed through.
+    let mut previous_port: Option<String> = None;
+    for (index, firmware) in firmwares.iter().enumerate() {
+        let last = index + 1 == firmwares.len();
+        if firmwares.len() > 1 {
+            log::info!(
+                "Package {} of {}: {}",
+                index + 1,
+                firmwares.len(),
+                firmware.describe()
+            );
+        }
+        if let Some(previous_port) = &previous_port {
+            // The device resets to activate the previous package. Only the last package is
+            // expected to leave the bootloader, so the device has to come back in bootloader mode.
+            wait_for_bootloader(
+                previous_port,
+                port_name.as_deref(),
+                vid,
+                pid,
+                serial_number.as_deref(),
+            )?;
+        }
+
+        let mut attempt = 0;
+        // Images of the package left to flash, after the device reset to activate the others.
+        let mut images = only.clone();
+        let result = loop {
+            let selected = select_port(
+                port_name.as_deref(),
+                vid,
+                pid,
+                serial_number.as_deref(),
+                baud_rate,
+                timeout,
+            );
+            // Each error comes with whether restarting the update might help.
+            let result = match selected {
+                Ok(port) => {
+                    let name = port.name.clone();
+                    let reboot = if wait_reboot && last {
+                        Some(&reboot)
+                    } else {
+                        None
+                    };
+                    match flash(port, firmware, new_update(&images), reboot, json) {
+                        Ok(()) => Ok(name),
+                        Err(e) => match activation_reset(&*e) {
+                            Some(remaining) => {
+                                wait_for_bootloader(
+                                    &name,
+                                    port_name.as_deref(),
+                                    vid,
+                                    pid,
+                                    serial_number.as_deref(),
+                                )?;
+                                images = remaining;
+                                continue;
+                            }
+                            None => {
+                                let transient = is_transient(&*e);
+                                Err((e, transient))
+                            }
+                        },
+                    }
+                }
+                // After a failed attempt, the device may still be re-enumerating.
+                Err(e) => Err((e, attempt != 0)),
+            };
+            match result {
+                Ok(name) => break Ok(name),
+                Err((e, true)) if attempt < update_retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "update failed ({}), reconnecting in {:?} (attempt {} of {})",
+                        e,
+                        update_retry_delay,
+                        attempt,
+                        update_retries
+                    );
+                    thread::sleep(update_retry_delay);
+                }
+                Err((e, _)) => break Err(e),
+            }
+        };
+
+        match result {
+            Ok(name) => {
+                previous_port = Some(name);
+                results.push(Ok(()));
+            }
+            Err(e) if firmwares.len() == 1 || is_cancelled(&*e) => return Err(e),
+            Err(e) => {
+                log::error!("failed to flash {}: {}", firmware.describe(), e);
+                if json {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "status": "error",
+                            "message": e.to_string(),
+                            "package": firmware.describe(),
+                        })
+                    );
+                }
+                results.push(Err(e));
+                if !continue_on_error {
+                    break;
+                }
+                // A failed update does not reset the device, so there is nothing to wait for.
+                previous_port = None;
+            }
+        }
+    }
+
+    if firmwares.len() == 1 {
+        return Ok(0);
+    }
+    if !json {
+        for (index, firmware) in firmwares.iter().enumerate() {
+            match results.get(index) {
+                Some(Ok(())) => println!("{}: ok", firmware.describe()),
+                Some(Err(e)) => println!("{}: failed: {}", firmware.describe(), e),
+                None => println!("{}: not flashed", firmware.describe()),
+            }
+        }
+    }
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    if failed != 0 {
+        log::error!("{} of {} packages failed", failed, firmwares.len());
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+/// Returns whether the update may succeed if it is restarted after failing with `e`, because
+/// the connection to the device was disrupted.
+fn is_transient(e: &(dyn Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<nrfdfu::DfuError>()
+            .map(nrfdfu::DfuError::root),
+        Some(
+            nrfdfu::DfuError::Serial(_)
+                | nrfdfu::DfuError::Ping(_)
+                | nrfdfu::DfuError::MalformedResponse(_)
+                | nrfdfu::DfuError::Crc { .. }
+                | nrfdfu::DfuError::OffsetMismatch { .. }
+        )
+    )
+}
+
+/// Returns whether `e` is the result of cancelling the update with Ctrl-C.
+fn is_cancelled(e: &(dyn Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<nrfdfu::DfuError>()
+            .map(nrfdfu::DfuError::root),
+        Some(nrfdfu::DfuError::Cancelled)
+    )
+}
+
+/// Returns the images left to flash if `e` is the device resetting to activate an image of a
+/// package that contains several.
+fn activation_reset(e: &(dyn Error + 'static)) -> Option<Vec<nrfdfu::ImageKind>> {
+    match e.downcast_ref::<nrfdfu::DfuError>()?.root() {
+        nrfdfu::DfuError::ResetAfterActivation { remaining, .. } => Some(remaining.clone()),
+        _ => None,
+    }
+}
+
+/// Flashes `firmware` onto the device at `port`, configured by `update`.
+///
+/// If `reboot` is given, the update only succeeds once the device has rebooted into the new
+/// firmware. If `json` is set, the result is printed to stdout as JSON on success.
+fn flash(
+    port: SelectedPort,
+    firmware: &Firmware,
+    update: nrfdfu::DfuUpdateBuilder<'_>,
+    reboot: Option<&RebootWait<'_>>,
+    json: bool,
+) -> Result<()> {
+    let device = port.describe();
+    log::info!("Flashing {}", device);
+
+    let report = match firmware {
+        Firmware::Package(zip_path) => update.run(port.port, zip_path)?,
+        Firmware::Checked { contents, .. } => {
+            let transport = nrfdfu::SerialTransport::new(port.port)?;
+            update
+                .build()
+                .run_transport_from_reader(Box::new(transport), io::Cursor::new(contents))?
+        }
+        Firmware::Files { dat, bin } => update.build().run_files(port.port, dat, bin)?,
+    };
+    // A skipped update leaves the device in the bootloader.
+    if let (Some(reboot), false) = (reboot, report.skipped) {
+        reboot.wait(&port.name)?;
+    }
+    print_report(
+        &report,
+        &device,
+        &port.name,
+        port.serial_number.as_deref(),
+        json,
+    );
+    Ok(())
+}
+
+/// Flashes `firmware` onto the device behind the serial-to-TCP bridge at `addr` (`--tcp`),
+/// configured by `update`. `timeout` applies to connecting and to every read and write.
+fn flash_tcp(
+    addr: &str,
+    firmware: &Firmware,
+    update: nrfdfu::DfuUpdateBuilder<'_>,
+    timeout: Duration,
+    json: bool,
+) -> Result<()> {
+    if let Firmware::Files { .. } = firmware {
+        return Err("`--tcp` can only be used with a DFU package".into());
+    }
+    log::info!("Flashing via {}", addr);
+    let transport = Box::new(connect_tcp(addr, timeout)?);
+    let report = match firmware {
+        Firmware::Package(zip_path) => update.build().run_transport(transport, zip_path)?,
+        Firmware::Checked { contents, .. } => update
+            .build()
+            .run_transport_from_reader(transport, io::Cursor::new(contents))?,
+        Firmware::Files { .. } => unreachable!(),
+    };
+    print_report(&report, addr, addr, None, json);
+    Ok(())
+}
+
+/// Connects to the serial-to-TCP bridge at `addr` (`--tcp`), waiting at most `timeout`.
+fn connect_tcp(addr: &str, timeout: Duration) -> Result<nrfdfu::TcpTransport> {
+    Ok(nrfdfu::TcpTransport::connect(addr, timeout)
+        .map_err(|e| format!("failed to connect to {}: {}", addr, e))?)
+}
+
+/// Logs the outcome of flashing `device`, which is reachable via `port`, and prints it to stdout
+/// as JSON if `json` is set.
+fn print_report(
+    report: &nrfdfu::UpdateReport,
+    device: &str,
+    port: &str,
+    serial_number: Option<&str>,
+    json: bool,
+) {
+    if report.skipped {
+        log::info!("{} is already up to date", device);
+    } else {
+        log::info!(
+            "sent {} bytes in {} objects in {:.1?} to {} (CRC 0x{:08x})",
+            report.bytes_sent,
+            report.chunks,
+            report.duration,
+            device,
+            report.final_crc
+        );
+    }
+    if json {
+        let images: Vec<_> = report
+            .images
+            .iter()
+            .map(|image| {
+                serde_json::json!({
+                    "kind": image.kind.to_string(),
+                    "dat_len": image.dat_len,
+                    "bin_len": image.bin_len,
+                    "padded_len": image.padded_len,
+                    "bin_crc": image.bin_crc,
+                    "fw_version": image.init_packet.fw_version,
+                    "hw_version": image.init_packet.hw_version,
+                    "sd_req": image.init_packet.sd_req,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::json!({
+                "status": "ok",
+                "bytes": report.bytes_sent,
+                "duration_ms": report.duration.as_millis() as u64,
+                "final_crc": report.final_crc,
+                "skipped": report.skipped,
+                "port": port,
+                "serial_number": serial_number,
+                "images": images,
+            })
+        );
+    }
+}
+
+/// Flashes `firmware` onto every device with the given `vid` and `pid`, one after the other, and
+/// prints a summary of the results.
+///
+/// A failure does not stop the remaining devices from being flashed. If `json` is set, the result
+/// for each device is printed to stdout as a line of JSON instead of the summary. Returns the
+/// process exit code: `0` if all devices were flashed successfully, `1` otherwise.
+fn flash_all<'a>(
+    vid: u16,
+    pid: u16,
+    baud_rate: u32,
+    timeout: Duration,
+    firmware: &Firmware,
+    new_update: impl Fn() -> nrfdfu::DfuUpdateBuilder<'a>,
+    json: bool,
+) -> Result<i32> {
+    let ports = matching_ports(vid, pid)?;
+    if ports.is_empty() {
+        return Err(format!(
+            "no matching USB serial device found (VID 0x{:04x}, PID 0x{:04x})",
+            vid, pid
+        )
+        .into());
+    }
+
+    let mut results = Vec::new();
+    for (name, usb) in ports {
+        let device = describe_device(&name, usb.serial_number.as_deref());
+        let result = open_port(&name, baud_rate, timeout).and_then(|port| {
+            let port = SelectedPort {
+                port,
+                name: name.clone(),
+                serial_number: usb.serial_number.clone(),
+            };
+            flash(port, firmware, new_update(), None, json)
+        });
+        let result = match result {
+            // Leave the remaining devices alone.
+            Err(e) if is_cancelled(&*e) => return Err(e),
+            result => result,
+        };
+        if let Err(e) = &result {
+            log::error!("failed to flash {}: {}", device, e);
+            if json {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "status": "error",
+                        "message": e.to_string(),
+                        "port": name,
+                        "serial_number": usb.serial_number,
+                    })
+                );
+            }
+        }
+        results.push((device, result));
+    }
+
+    if !json {
+        for (device, result) in &results {
+            match result {
+                Ok(()) => println!("{}: ok", device),
+                Err(e) => println!("{}: failed: {}", device, e),
+            }
+        }
+    }
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed != 0 {
+        log::error!("{} of {} devices failed", failed, results.len());
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+fn check_no_remaining_args(args: pico_args::Arguments) -> Result<()> {
+    let remaining = args.finish();
+    if !remaining.is_empty() {
+        return Err(format!("unexpected arguments: {:?}", remaining).into());
+    }
+    Ok(())
+}
+
+/// Returns the remaining free-standing arguments, failing if any of them looks like an option.
+fn free_args(args: pico_args::Arguments) -> Result<Vec<String>> {
+    let remaining = args.finish();
+    if remaining
+        .iter()
+        .any(|arg| arg.to_string_lossy().starts_with('-'))
+    {
+        return Err(format!("unexpected arguments: {:?}", remaining).into());
+    }
+    remaining
+        .into_iter()
+        .map(|arg| {
+            arg.into_string()
+                .map_err(|arg| format!("{:?} is not valid UTF-8", arg).into())
+        })
+        .collect()
+}
+
+/// The firmware to flash, as given on the command line.
+enum Firmware {
+    /// Path to a DFU package.
+    Package(String),
+    /// A DFU package whose hash was checked (`--sha256`). It is flashed from `contents`, so that
+    /// the file can't change after it was checked.
+    Checked { path: String, contents: Vec<u8> },
+    /// Paths to a loose init packet and firmware image (`--dat` and `--bin`).
+    Files { dat: String, bin: String },
+}
+
+impl Firmware {
+    /// Returns a description of the firmware for log messages and summaries.
+    fn describe(&self) -> String {
+        match self {
+            Firmware::Package(zip_path) | Firmware::Checked { path: zip_path, .. } => {
+                zip_path.clone()
+            }
+            Firmware::Files { dat, bin } => format!("{} and {}", dat, bin),
+        }
+    }
+}
+
+/// Returns a progress callback that draws a progress bar on stderr (`--progress`).
+#[cfg(feature = "indicatif")]
+fn progress_bar() -> impl FnMut(nrfdfu::Progress) {
+    let bar = indicatif::ProgressBar::new(0);
+    bar.set_style(
+        indicatif::ProgressStyle::with_template(
+            "{wide_bar} {bytes}/{total_bytes} ({percent}%), {bytes_per_sec}, ETA {eta}",
+        )
+        .expect("invalid progress bar template"),
+    );
+    move |progress| {
+        bar.set_length(progress.total);
+        bar.set_position(progress.sent);
+        if progress.sent == progress.total {
+            bar.finish();
+        }
+    }
+}
+
+/// Reads the DFU package at `path` and checks that its SHA-256 hash is `expected`, before anything
+/// else reads it.
+fn check_sha256(path: &str, expected: &[u8]) -> Result<Firmware> {
+    let contents = fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let actual = Sha256::digest(&contents);
+    if actual.as_slice() != expected {
+        return Err(format!(
+            "SHA-256 of {} is {}, expected {}",
+            path,
+            to_hex(&actual),
+            to_hex(expected)
+        )
+        .into());
+    }
+    log::debug!("SHA-256 of {} matches", path);
+    Ok(Firmware::Checked {
+        path: path.to_string(),
+        contents,
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Validates `firmware` and prints its contents, without opening a serial port.
+fn check_package(firmware: &Firmware, options: &nrfdfu::UpdateOptions) -> Result<i32> {
+    let images = match firmware {
+        Firmware::Package(zip_path) | Firmware::Checked { path: zip_path, .. } => {
+            nrfdfu::check_package(zip_path, options)?
+        }
+        Firmware::Files { dat, bin } => nrfdfu::check_files(dat, bin)?,
+    };
+    for image in images {
+        println!(
+            "{}: init packet {} ({} bytes), firmware {} ({} bytes, {} bytes padded, CRC32 0x{:08x})",
+            image.kind,
+            image.dat_file,
+            image.dat_len,
+            image.bin_file,
+            image.bin_len,
+            image.padded_len,
+            image.bin_crc,
+        );
+        println!("  declared in init packet: {}", image.init_packet);
+        if let Some(data) = &image.init_packet_data {
+            println!("  declared in manifest: {}", data);
+        }
+    }
+    match firmware {
+        Firmware::Package(zip_path) | Firmware::Checked { path: zip_path, .. } => {
+            println!("{} is a valid DFU package", zip_path)
+        }
+        Firmware::Files { dat, bin } => {
+            println!("{} is a valid init packet for {}", dat, bin)
+        }
+    }
+    Ok(0)
+}
+
+/// Prints all serial ports belonging to a USB device with the given `vid` and `pid`.
+///
+/// Returns the process exit code: `0` if any ports were found, `EXIT_NO_DEVICES` otherwise.
+fn list_ports(vid: u16, pid: u16) -> Result<i32> {
+    let ports = matching_ports(vid, pid)?;
+    if ports.is_empty() {
+        eprintln!(
+            "no matching USB serial device found (VID 0x{:04x}, PID 0x{:04x})",
+            vid, pid
+        );
+        return Ok(EXIT_NO_DEVICES);
+    }
+
+    for (port_name, usb) in &ports {
+        println!(
+            "{}: VID 0x{:04x}, PID 0x{:04x}, serial number {}, manufacturer {}",
+            port_name,
+            usb.vid,
+            usb.pid,
+            usb.serial_number.as_deref().unwrap_or("<unknown>"),
+            usb.manufacturer.as_deref().unwrap_or("<unknown>"),
+        );
+    }
+    Ok(0)
+}
+
+/// Compares `firmware` with the data the device on `port` holds, without writing anything to it,
+/// and prints the result for every object.
+///
+/// Returns the process exit code: `0` if the device holds an image completely, `EXIT_MISMATCH`
+/// otherwise.
+fn verify_device(
+    port: SelectedPort,
+    firmware: &Firmware,
+    options: &nrfdfu::UpdateOptions,
+) -> Result<i32> {
+    log::info!("Verifying {}", port.describe());
+    let reports = match firmware {
+        Firmware::Package(zip_path) | Firmware::Checked { path: zip_path, .. } => {
+            nrfdfu::verify(port.port, zip_path, options)?
+        }
+        Firmware::Files { dat, bin } => nrfdfu::verify_files(port.port, dat, bin, options)?,
+    };
+
+    for report in &reports {
+        for (name, object) in &[
+            ("init packet", report.init_packet),
+            ("firmware", report.firmware),
+        ] {
+            let outcome = if object.is_complete() {
+                "match"
+            } else if object.offset == 0 {
+                "not present"
+            } else if object.crc_matches {
+                "partial match"
+            } else {
+                "mismatch"
+            };
+            println!(
+                "{} {}: {} ({} of {} bytes on the device)",
+                report.kind, name, outcome, object.offset, object.len
+            );
+        }
+    }
+
+    let complete = reports
+        .iter()
+        .any(|report| report.init_packet.is_complete() && report.firmware.is_complete());
+    Ok(if complete { 0 } else { EXIT_MISMATCH })
+}
+
+/// Connects to the bootloader via `transport` and prints what it reports about itself, without
+/// flashing anything.
+fn print_info(
+    transport: Box<dyn nrfdfu::Transport>,
+    options: &nrfdfu::UpdateOptions,
+) -> Result<i32> {
+    let mut conn = nrfdfu::BootloaderConnection::connect_with_options(transport, options)?;
+
+    println!("protocol version: {}", conn.fetch_protocol_version()?);
+    println!("MTU: {} bytes", conn.fetch_mtu()?);
+    println!("hardware: {}", conn.fetch_hardware_version()?);
+    for (name, object) in &[
+        ("command", conn.select_object_command()?),
+        ("data", conn.select_object_data()?),
+    ] {
+        println!(
+            "{} object: max size {} bytes, {} bytes received (CRC 0x{:08x})",
+            name, object.max_size, object.offset, object.crc
+        );
+    }
+    match conn.firmware_info()? {
+        Some(images) => {
+            for firmware in images {
+                println!(
+                    "{:?} firmware: version {}, {} bytes at 0x{:08x}",
+                    firmware.fw_type, firmware.version, firmware.len, firmware.addr
+                );
+            }
+        }
+        None => println!("firmware: not reported by the bootloader"),
+    }
+    Ok(0)
+}
+
+/// Returns the names and USB info of all serial ports with the given `vid` and `pid`.
+fn matching_ports(vid: u16, pid: u16) -> Result<Vec<(String, UsbPortInfo)>> {
+    Ok(available_ports()?
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(usb) if usb.vid == vid && usb.pid == pid => {
+                Some((port.port_name, usb))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Waits until a serial port belonging to a USB device with the given `vid`, `pid` and (optional)
+/// `serial_number` appears.
+#[cfg(feature = "trigger")]
+fn wait_for_port(vid: u16, pid: u16, serial_number: Option<&str>) -> Result<()> {
+    if !poll(TRIGGER_TIMEOUT, || port_present(vid, pid, serial_number))? {
+        return Err(format!(
+            "bootloader did not show up within {:?} after triggering DFU mode \
+            (VID 0x{:04x}, PID 0x{:04x})",
+            TRIGGER_TIMEOUT, vid, pid
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Waits for the device to come back in bootloader mode after it reset to activate a package that
+/// was flashed through `previous_port`.
+///
+/// If `port_name` is given, that port has to come back. Otherwise, a port belonging to a USB
+/// device with the given `vid`, `pid` and (optional) `serial_number` has to appear.
+fn wait_for_bootloader(
+    previous_port: &str,
+    port_name: Option<&str>,
+    vid: u16,
+    pid: u16,
+    serial_number: Option<&str>,
+) -> Result<()> {
+    let present = |name: &str| -> Result<bool> {
+        Ok(available_ports()?.iter().any(|port| port.port_name == name))
+    };
+    log::info!("Waiting for the device to reset...");
+    if !poll(RESET_DETECT_TIMEOUT, || Ok(!present(previous_port)?))? {
+        log::debug!(
+            "{} did not disappear, assuming the device reset without re-enumerating",
+            previous_port
+        );
+        return Ok(());
+    }
+
+    let back = match port_name {
+        Some(port_name) => poll(TRIGGER_TIMEOUT, || present(port_name))?,
+        None => poll(TRIGGER_TIMEOUT, || port_present(vid, pid, serial_number))?,
+    };
+    if !back {
+        return Err(format!(
+            "bootloader did not come back within {:?} after activating the previous package",
+            TRIGGER_TIMEOUT
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Returns whether a serial port belonging to a USB device with the given `vid`, `pid` and
+/// (optional) `serial_number` is present.
+fn port_present(vid: u16, pid: u16, serial_number: Option<&str>) -> Result<bool> {
+    Ok(matching_ports(vid, pid)?
+        .iter()
+        .any(|(_, usb)| serial_number.is_none() || usb.serial_number.as_deref() == serial_number))
+}
+
+/// Calls `done` until it returns `true`, returning `false` if that does not happen within
+/// `timeout`.
+fn poll(timeout: Duration, mut done: impl FnMut() -> Result<bool>) -> Result<bool> {
+    let start = Instant::now();
+    loop {
+        if done()? {
+            return Ok(true);
+        }
+        if start.elapsed() > timeout {
+            return Ok(false);
+        }
+        thread::sleep(PORT_POLL_INTERVAL);
+    }
+}
+
+/// What to wait for after flashing, to confirm that the device rebooted into the new firmware
+/// (`--wait-reboot`).
+struct RebootWait<'a> {
+    /// VID of the bootloader, whose port has to disappear.
+    vid: u16,
+    /// PID of the bootloader.
+    pid: u16,
+    /// VID and PID of the application, whose port has to appear, if known.
+    app: Option<(u16, u16)>,
+    /// USB serial number the application is expected to report, if known.
+    serial_number: Option<&'a str>,
+    /// Time allowed for the whole reboot.
+    timeout: Duration,
+}
+
+impl RebootWait<'_> {
+    /// Waits until the bootloader port `port_name` disappears and, if the application's VID and
+    /// PID are known, until the application's port appears.
+    fn wait(&self, port_name: &str) -> Result<()> {
+        let start = Instant::now();
+        log::info!("Waiting for the device to reboot...");
+        let gone = poll(self.timeout, || {
+            Ok(!matching_ports(self.vid, self.pid)?
+                .iter()
+                .any(|(name, _)| name == port_name))
+        })?;
+        if !gone {
+            return Err(format!(
+                "bootloader port {} did not disappear within {:?} after flashing",
+                port_name, self.timeout
+            )
+            .into());
+        }
+
+        if let Some((vid, pid)) = self.app {
+            let remaining = self.timeout.saturating_sub(start.elapsed());
+            if !poll(remaining, || port_present(vid, pid, self.serial_number))? {
+                return Err(format!(
+                    "application did not show up within {:?} after flashing \
+                    (VID 0x{:04x}, PID 0x{:04x})",
+                    self.timeout, vid, pid
+                )
+                .into());
+            }
+        }
+        log::info!("Device rebooted after {:.1?}", start.elapsed());
+        Ok(())
+    }
+}
+
+/// A serial port selected for flashing.
+struct SelectedPort {
+    port: Box<dyn SerialPort>,
+    name: String,
+    /// USB serial number of the device, if known.
+    serial_number: Option<String>,
+}
+
+impl SelectedPort {
+    /// Returns a description of the port for log messages, e.g. `/dev/ttyACM0 (serial number
+    /// E4C1F2A39B07)`.
+    fn describe(&self) -> String {
+        describe_device(&self.name, self.serial_number.as_deref())
+    }
+}
+
+fn describe_device(port_name: &str, serial_number: Option<&str>) -> String {
+    match serial_number {
+        Some(serial_number) => format!("{} (serial number {})", port_name, serial_number),
+        None => port_name.to_string(),
+    }
+}
+
+/// Opens the single serial port belonging to a USB device with the given `vid` and `pid`.
+///
+/// If `port_name` is given, that port is opened directly and no autodetection takes place.
+/// Otherwise, if `serial_number` is given, only the device with that USB serial number is
+/// considered. The port is opened with `baud_rate`, and `timeout` is applied to all reads and
+/// writes on it.
+fn select_port(
+    port_name: Option<&str>,
+    vid: u16,
+    pid: u16,
+    serial_number: Option<&str>,
+    baud_rate: u32,
+    timeout: Duration,
+) -> Result<SelectedPort> {
+    if let Some(port_name) = port_name {
+        log::debug!("opening {} (selected via --port)", port_name);
+        // The port need not belong to a device with the expected VID and PID, so look at all of
+        // them to find its serial number.
+        let serial_number = available_ports()?
+            .into_iter()
+            .find(|port| port.port_name == port_name)
+            .and_then(|port| match port.port_type {
+                SerialPortType::UsbPort(usb) => usb.serial_number,
+                _ => None,
+            });
+        return Ok(SelectedPort {
+            port: open_port(port_name, baud_rate, timeout)?,
+            name: port_name.to_string(),
+            serial_number,
+        });
+    }
+
+    let mut matching_ports = matching_ports(vid, pid)?;
+
+    if let Some(serial_number) = serial_number {
+        let available_serials = matching_ports
+            .iter()
+            .map(|(_, usb)| usb.serial_number.as_deref().unwrap_or("<unknown>"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        matching_ports.retain(|(_, usb)| usb.serial_number.as_deref() == Some(serial_number));
+
+        if matching_ports.is_empty() {
+            return Err(format!(
+                "no matching USB serial device with serial number {} found (available: {})",
+                serial_number,
+                if available_serials.is_empty() {
+                    "none"
+                } else {
+                    &available_serials
+                }
+            )
+            .into());
+        }
+    }
+
+    match matching_ports.len() {
+        0 => Err(format!(
+            "no matching USB serial device found (VID 0x{:04x}, PID 0x{:04x}).\n\
+            Remember to put the device in bootloader mode!",
+            vid, pid
+        )
+        .into()),
+        1 => {
+            let (port, usb) = matching_ports.remove(0);
+            log::debug!("opening {} ({:?})", port, usb);
+            Ok(SelectedPort {
+                port: open_port(&port, baud_rate, timeout)?,
+                name: port,
+                serial_number: usb.serial_number,
+            })
+        }
+        _ => Err("multiple matching USB serial devices found.\n\
+            This utility only works when a single device is in bootloader mode, \
+            or when the device is selected with `--serial` or `--port`."
+            .to_string()
+            .into()),
+    }
+}
+
+fn open_port(port_name: &str, baud_rate: u32, timeout: Duration) -> Result<Box<dyn SerialPort>> {
+    serialport::new(port_name, baud_rate)
+        .timeout(timeout)
+        .open()
+        .map_err(|e| match e.kind() {
+            serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied) => format!(
+                "permission denied opening {}; {}",
+                port_name, PERMISSION_HINT
+            )
+            .into(),
+            _ => format!("failed to open {}: {}", port_name, e).into(),
+        })
+}
+
+/// Parses a USB vendor or product ID, given in decimal or (with a `0x` prefix) in hexadecimal.
+fn parse_u16(s: &str) -> std::result::Result<u16, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("'{}' is not a valid 16-bit ID: {}", s, e))
+}
+
+/// Parses a log level like `debug`.
+fn parse_log_level(s: &str) -> std::result::Result<LevelFilter, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "error" => Ok(LevelFilter::Error),
+        "warn" => Ok(LevelFilter::Warn),
+        "info" => Ok(LevelFilter::Info),
+        "debug" => Ok(LevelFilter::Debug),
+        "trace" => Ok(LevelFilter::Trace),
+        _ => Err(format!(
+            "'{}' is not a valid log level (expected error, warn, info, debug or trace)",
+            s
+        )),
+    }
+}
+
+/// Parses the kind of a firmware image, as named in the manifest of a DFU package.
+fn parse_image_kind(s: &str) -> std::result::Result<nrfdfu::ImageKind, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "application" => Ok(nrfdfu::ImageKind::Application),
+        "bootloader" => Ok(nrfdfu::ImageKind::Bootloader),
+        "softdevice" => Ok(nrfdfu::ImageKind::Softdevice),
+        "softdevice_bootloader" => Ok(nrfdfu::ImageKind::SoftdeviceBootloader),
+        _ => Err(format!(
+            "'{}' is not a valid image kind \
+            (expected application, bootloader, softdevice or softdevice_bootloader)",
+            s
+        )),
+    }
+}
+
+/// Parses a part number like `0x52840`, `52840` or `nRF52840`, which is always hexadecimal.
+fn parse_part(s: &str) -> std::result::Result<u32, String> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .or_else(|| {
+            s.get(..3)
+                .filter(|p| p.eq_ignore_ascii_case("nrf"))
+                .map(|_| &s[3..])
+        })
+        .unwrap_or(s);
+    u32::from_str_radix(hex, 16).map_err(|e| format!("'{}' is not a valid part number: {}", s, e))
+}
+
+/// Parses a SHA-256 hash given as 64 hexadecimal digits.
+fn parse_sha256(s: &str) -> std::result::Result<Vec<u8>, String> {
+    let invalid = || {
+        format!(
+            "'{}' is not a valid SHA-256 hash (expected 64 hex digits)",
+            s
+        )
+    };
+    if s.len() != 64 || !s.is_ascii() {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
@@ -0,0 +1,461 @@
+//! Builder-style configuration of firmware updates.
+
+use std::io::{Read, Seek};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+use crate::{
+    CrcAlgorithm, ImageKind, OpCode, Progress, Result, Transport, UpdateOptions, UpdateReport,
+};
+
+/// A configured firmware update, created with [`DfuUpdate::builder`].
+///
+/// ```no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let port = serialport::new("/dev/ttyACM0", 115200).open()?;
+/// let report = nrfdfu::DfuUpdate::builder()
+///     .prn(8)
+///     .expected_hw(0x52840)
+///     .progress_callback(|p| println!("{}/{}", p.sent, p.total))
+///     .run(port, "firmware.zip")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct DfuUpdate<'a> {
+    options: UpdateOptions,
+    progress: Option<Box<dyn FnMut(Progress) + 'a>>,
+}
+
+impl<'a> DfuUpdate<'a> {
+    /// Returns a builder with the default [`UpdateOptions`] and no progress callback.
+    pub fn builder() -> DfuUpdateBuilder<'a> {
+        DfuUpdateBuilder {
+            update: DfuUpdate {
+                options: UpdateOptions::default(),
+                progress: None,
+            },
+        }
+    }
+
+    /// Returns the options this update was configured with.
+    pub fn options(&self) -> &UpdateOptions {
+        &self.options
+    }
+
+    /// Flashes the DFU package at `zip_path` onto the bootloader connected via `port`.
+    pub fn run(mut self, port: Box<dyn SerialPort>, zip_path: &str) -> Result<UpdateReport> {
+        let progress = &mut self.progress;
+        crate::run_with_progress(port, zip_path, &self.options, |p| {
+            if let Some(progress) = progress {
+                progress(p);
+            }
+        })
+    }
+
+    /// Flashes the DFU package at `zip_path` onto the bootloader connected via `transport`. See
+    /// [`run_with_transport`](crate::run_with_transport).
+    pub fn run_transport(
+        mut self,
+        transport: Box<dyn Transport>,
+        zip_path: &str,
+    ) -> Result<UpdateReport> {
+        let progress = &mut self.progress;
+        crate::run_with_transport(transport, zip_path, &self.options, |p| {
+            if let Some(progress) = progress {
+                progress(p);
+            }
+        })
+    }
+
+    /// Flashes the DFU package read from `reader` onto the bootloader connected via `transport`.
+    /// See [`run_from_reader_with_transport`](crate::run_from_reader_with_transport).
+    pub fn run_transport_from_reader<R: Read + Seek>(
+        mut self,
+        transport: Box<dyn Transport>,
+        reader: R,
+    ) -> Result<UpdateReport> {
+        let progress = &mut self.progress;
+        crate::run_from_reader_with_transport(transport, reader, &self.options, |p| {
+            if let Some(progress) = progress {
+                progress(p);
+            }
+        })
+    }
+
+    /// Flashes the loose init packet at `dat_path` and firmware image at `bin_path` onto the
+    /// bootloader connected via `port`. See [`run_with_files`](crate::run_with_files).
+    pub fn run_files(
+        mut self,
+        port: Box<dyn SerialPort>,
+        dat_path: &str,
+        bin_path: &str,
+    ) -> Result<UpdateReport> {
+        let progress = &mut self.progress;
+        crate::run_with_files(port, dat_path, bin_path, &self.options, |p| {
+            if let Some(progress) = progress {
+                progress(p);
+            }
+        })
+    }
+}
+
+/// Builder for [`DfuUpdate`].
+pub struct DfuUpdateBuilder<'a> {
+    update: DfuUpdate<'a>,
+}
+
+impl<'a> DfuUpdateBuilder<'a> {
+    /// See [`UpdateOptions::prn`].
+    pub fn prn(mut self, prn: u16) -> Self {
+        self.update.options.prn = prn;
+        self
+    }
+
+    /// See [`UpdateOptions::chunk_retries`].
+    pub fn chunk_retries(mut self, chunk_retries: u32) -> Self {
+        self.update.options.chunk_retries = chunk_retries;
+        self
+    }
+
+    /// See [`UpdateOptions::handshake_retries`].
+    pub fn handshake_retries(mut self, handshake_retries: u32) -> Self {
+        self.update.options.handshake_retries = handshake_retries;
+        self
+    }
+
+    /// See [`UpdateOptions::read_retries`].
+    pub fn read_retries(mut self, read_retries: u32) -> Self {
+        self.update.options.read_retries = read_retries;
+        self
+    }
+
+    /// Only flash devices reporting the part number `part` (e.g. `0x52840`).
+    ///
+    /// See [`UpdateOptions::expected_part`].
+    pub fn expected_hw(mut self, part: u32) -> Self {
+        self.update.options.expected_part = Some(part);
+        self
+    }
+
+    /// See [`UpdateOptions::strict_dfu_version`].
+    pub fn strict_dfu_version(mut self, strict: bool) -> Self {
+        self.update.options.strict_dfu_version = strict;
+        self
+    }
+
+    /// See [`UpdateOptions::ignore_sd_req`].
+    pub fn ignore_sd_req(mut self, ignore: bool) -> Self {
+        self.update.options.ignore_sd_req = ignore;
+        self
+    }
+
+    /// Also talks to bootloaders reporting protocol version `version`, at your own risk.
+    ///
+    /// Can be called multiple times to accept several versions. See
+    /// [`UpdateOptions::accepted_protocol_versions`].
+    pub fn accept_protocol_version(mut self, version: u8) -> Self {
+        self.update.options.accepted_protocol_versions.push(version);
+        self
+    }
+
+    /// Talks to bootloaders reporting any protocol version, at your own risk. See
+    /// [`UpdateOptions::force_protocol_version`].
+    pub fn force_protocol_version(mut self, force: bool) -> Self {
+        self.update.options.force_protocol_version = force;
+        self
+    }
+
+    /// See [`UpdateOptions::manifest_name`].
+    pub fn manifest_name(mut self, name: impl Into<String>) -> Self {
+        self.update.options.manifest_name = name.into();
+        self
+    }
+
+    /// Only flashes the images of kind `kind` from the package.
+    ///
+    /// Can be called multiple times to flash several kinds of images. See [`UpdateOptions::only`].
+    pub fn only(mut self, kind: ImageKind) -> Self {
+        self.update.options.only.push(kind);
+        self
+    }
+
+    /// Cancels the update once `cancel` is set. See [`UpdateOptions::cancel`].
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.update.options.cancel = Some(cancel);
+        self
+    }
+
+    /// See [`UpdateOptions::skip_if_current`].
+    pub fn skip_if_current(mut self, skip: bool) -> Self {
+        self.update.options.skip_if_current = skip;
+        self
+    }
+
+    /// See [`UpdateOptions::reset_first`].
+    pub fn reset_first(mut self, reset: bool) -> Self {
+        self.update.options.reset_first = reset;
+        self
+    }
+
+    /// See [`UpdateOptions::pad_byte`].
+    pub fn pad_byte(mut self, pad_byte: u8) -> Self {
+        self.update.options.pad_byte = pad_byte;
+        self
+    }
+
+    /// See [`UpdateOptions::pad`].
+    pub fn pad(mut self, pad: bool) -> Self {
+        self.update.options.pad = pad;
+        self
+    }
+
+    /// Waits up to `timeout` for the device to activate each image. See
+    /// [`UpdateOptions::activation_timeout`].
+    pub fn activation_timeout(mut self, timeout: Duration) -> Self {
+        self.update.options.activation_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails the update if the device does not respond to executing the last data object of an
+    /// image. See [`UpdateOptions::require_activation_response`].
+    pub fn require_activation_response(mut self, require: bool) -> Self {
+        self.update.options.require_activation_response = require;
+        self
+    }
+
+    /// Uses `timeout` for reads and writes, overriding the timeout of the port. See
+    /// [`UpdateOptions::timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.update.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Waits up to `timeout` for the responses to requests with `opcode`. Can be called multiple
+    /// times for different opcodes. See [`UpdateOptions::opcode_timeouts`].
+    pub fn opcode_timeout(mut self, opcode: OpCode, timeout: Duration) -> Self {
+        self.update.options.opcode_timeouts.insert(opcode, timeout);
+        self
+    }
+
+    /// See [`UpdateOptions::crc_algorithm`].
+    pub fn crc_algorithm(mut self, algorithm: CrcAlgorithm) -> Self {
+        self.update.options.crc_algorithm = algorithm;
+        self
+    }
+
+    /// See [`UpdateOptions::mtu_fallback_after`].
+    pub fn mtu_fallback_after(mut self, failures: u32) -> Self {
+        self.update.options.mtu_fallback_after = failures;
+        self
+    }
+
+    /// Never uses an MTU larger than `max_mtu` bytes. See [`UpdateOptions::max_mtu`].
+    pub fn max_mtu(mut self, max_mtu: u16) -> Self {
+        self.update.options.max_mtu = max_mtu;
+        self
+    }
+
+    /// Pauses for `delay` between two successive write requests. See
+    /// [`UpdateOptions::inter_packet_delay`].
+    pub fn inter_packet_delay(mut self, delay: Duration) -> Self {
+        self.update.options.inter_packet_delay = delay;
+        self
+    }
+
+    /// See [`UpdateOptions::resume`].
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.update.options.resume = resume;
+        self
+    }
+
+    /// Invokes `progress` after the init packet and every firmware data object have been
+    /// transferred.
+    pub fn progress_callback(mut self, progress: impl FnMut(Progress) + 'a) -> Self {
+        self.update.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Finishes the configuration.
+    pub fn build(self) -> DfuUpdate<'a> {
+        self.update
+    }
+
+    /// Shorthand for `.build().run(port, zip_path)`.
+    pub fn run(self, port: Box<dyn SerialPort>, zip_path: &str) -> Result<UpdateReport> {
+        self.build().run(port, zip_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::init_packet::build_init_packet;
+    use crate::mock_device::MockDevice;
+    use crate::zip_file::zip_package;
+    use crate::DfuError;
+
+    /// Returns a DFU package containing the application `bin`.
+    fn package(bin: &[u8]) -> Cursor<Vec<u8>> {
+        zip_package(&[
+            (
+                "manifest.json",
+                br#"{"manifest":{"application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#,
+            ),
+            ("app.dat", &build_init_packet(bin)),
+            ("app.bin", bin),
+        ])
+    }
+
+    fn count(requests: &[u8], opcode: OpCode) -> usize {
+        requests.iter().filter(|&&op| op == opcode as u8).count()
+    }
+
+    #[test]
+    fn defaults() {
+        let device = MockDevice::new(20, 256, 64);
+        let bin = (0..150).collect::<Vec<u8>>();
+        let report = DfuUpdate::builder()
+            .build()
+            .run_transport_from_reader(device.connect(), package(&bin))
+            .unwrap();
+
+        // The image is padded with 0xFF to a multiple of 4 bytes, and sent in full.
+        let mut padded = bin.clone();
+        padded.extend_from_slice(&[0xFF, 0xFF]);
+        assert_eq!(device.firmware(), padded);
+        assert_eq!(report.bytes_sent, 152);
+        assert_eq!(report.chunks, 3);
+        assert!(!report.skipped);
+        assert_eq!(count(&device.requests(), OpCode::Abort), 0);
+    }
+
+    #[test]
+    fn configured() {
+        let device = MockDevice::new(20, 256, 64);
+        let bin = (0..150).collect::<Vec<u8>>();
+        let progress_calls = Cell::new(0);
+        let report = DfuUpdate::builder()
+            .pad_byte(0x00)
+            .max_mtu(14)
+            .prn(2)
+            .reset_first(true)
+            .expected_hw(0x52840)
+            .progress_callback(|_| progress_calls.set(progress_calls.get() + 1))
+            .build()
+            .run_transport_from_reader(device.connect(), package(&bin))
+            .unwrap();
+
+        let mut padded = bin.clone();
+        padded.extend_from_slice(&[0x00, 0x00]);
+        assert_eq!(device.firmware(), padded);
+        assert_eq!(report.bytes_sent, 152);
+        assert!(progress_calls.get() > 0);
+
+        let requests = device.requests();
+        assert_eq!(count(&requests, OpCode::Abort), 1);
+        // With an MTU of 14, each write carries at most 13 bytes: 5 writes for each of the two
+        // full 64 byte objects, and 2 for the remaining 24 bytes, after those of the init packet.
+        let init_writes = build_init_packet(&bin).len().div_ceil(13);
+        assert_eq!(count(&requests, OpCode::Write), init_writes + 12);
+    }
+
+    #[test]
+    fn unpadded() {
+        let device = MockDevice::new(20, 256, 64);
+        let bin = (0..150).collect::<Vec<u8>>();
+        let report = DfuUpdate::builder()
+            .pad(false)
+            .build()
+            .run_transport_from_reader(device.connect(), package(&bin))
+            .unwrap();
+
+        assert_eq!(device.firmware(), bin);
+        assert_eq!(report.bytes_sent, 150);
+    }
+
+    #[test]
+    fn wrong_part() {
+        let device = MockDevice::new(20, 256, 64);
+        let err = DfuUpdate::builder()
+            .expected_hw(0x52832)
+            .build()
+            .run_transport_from_reader(device.connect(), package(&[1, 2, 3, 4]))
+            .unwrap_err();
+
+        assert!(
+            matches!(
+                err,
+                DfuError::WrongPart {
+                    expected: 0x52832,
+                    actual: 0x52840
+                }
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(count(&device.requests(), OpCode::Write), 0);
+    }
+
+    #[test]
+    fn cancelled() {
+        let device = MockDevice::new(20, 256, 64);
+        let err = DfuUpdate::builder()
+            .cancel_flag(Arc::new(AtomicBool::new(true)))
+            .build()
+            .run_transport_from_reader(device.connect(), package(&[1, 2, 3, 4]))
+            .unwrap_err();
+
+        assert!(matches!(err, DfuError::Cancelled), "{:?}", err);
+        assert!(device.firmware().is_empty());
+    }
+
+    #[test]
+    fn reset_after_activation() {
+        let sd = (0..40).collect::<Vec<u8>>();
+        let app = (0..150).collect::<Vec<u8>>();
+        let zip = zip_package(&[
+            (
+                "manifest.json",
+                br#"{"manifest":{"softdevice":{"bin_file":"sd.bin","dat_file":"sd.dat"},
+                    "application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#,
+            ),
+            ("sd.dat", &build_init_packet(&sd)),
+            ("sd.bin", &sd),
+            ("app.dat", &build_init_packet(&app)),
+            ("app.bin", &app),
+        ]);
+
+        // The device resets once the SoftDevice is activated, so the update stops there.
+        let device = MockDevice::new(20, 256, 64);
+        let err = DfuUpdate::builder()
+            .build()
+            .run_transport_from_reader(device.connect(), zip.clone())
+            .unwrap_err();
+        match err {
+            DfuError::ResetAfterActivation { flashed, remaining } => {
+                assert_eq!(flashed, ImageKind::Softdevice);
+                assert_eq!(remaining, vec![ImageKind::Application]);
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+        assert_eq!(device.firmware(), sd);
+
+        // The remaining image is flashed over a new connection.
+        let device = MockDevice::new(20, 256, 64);
+        let report = DfuUpdate::builder()
+            .only(ImageKind::Application)
+            .build()
+            .run_transport_from_reader(device.connect(), zip)
+            .unwrap();
+        let mut padded = app.clone();
+        padded.extend_from_slice(&[0xFF, 0xFF]);
+        assert_eq!(device.firmware(), padded);
+        assert_eq!(report.bytes_sent, 152);
+    }
+}
@@ -0,0 +1,934 @@
+//! The protocol logic behind [`BootloaderConnection`](crate::BootloaderConnection) and the async
+//! API.
+//!
+//! [`Connection`] is written once, with `async` methods, against the [`Link`] trait. Blocking
+//! transports implement `Link` without ever suspending, so the blocking API drives these futures
+//! with [`block_on`]. The async API implements `Link` for `AsyncSerialTransport` and awaits them.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+use crate::error::ResultExt;
+use crate::messages::*;
+use crate::{
+    check_crc, check_image_crc, check_protocol_version, check_receipt, format_throughput,
+    is_cancelled, is_stale, is_timeout, is_unsupported, locate_crc_error, read_retry_delay,
+    reduced_chunk_size, CrcAlgorithm, DfuError, ObjectReader, Progress, Result, Transport,
+    UpdateReport, ACTIVATION_HEARTBEAT_INTERVAL, DEFAULT_MAX_MTU, DEFAULT_PAD_BYTE,
+    HANDSHAKE_RETRY_DELAY, MIN_FRAME_SIZE, THROUGHPUT_LOG_INTERVAL,
+};
+
+/// Stand-in for `tracing::Span` without the `tracing` feature, see the `span!` macro.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Span;
+
+/// The I/O a [`Connection`] performs, see [`Transport`] for the meaning of the methods.
+pub(crate) trait Link {
+    async fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    async fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    fn max_frame_size(&self, mtu: u16) -> usize;
+
+    fn timeout(&self) -> Option<Duration>;
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Pauses for `duration`, e.g. between retries.
+    async fn sleep(&mut self, duration: Duration);
+}
+
+impl Link for Box<dyn Transport> {
+    async fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        Transport::send_frame(&mut **self, frame)
+    }
+
+    async fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        Transport::recv_frame(&mut **self, buf)
+    }
+
+    fn max_frame_size(&self, mtu: u16) -> usize {
+        Transport::max_frame_size(&**self, mtu)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Transport::timeout(&**self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Transport::set_timeout(&mut **self, timeout)
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Runs `future`, which may only await a blocking [`Link`], to completion.
+///
+/// A blocking `Link` does all its work before returning, so such a future completes the first time
+/// it is polled.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    match future
+        .as_mut()
+        .poll(&mut Context::from_waker(&Waker::from(Arc::new(NoopWaker))))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("blocking transports never suspend"),
+    }
+}
+
+/// Waker for [`block_on`], whose futures never need waking.
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Awaits `future` inside `span` (see the `span!` macro), which is entered whenever the future is
+/// polled, so that async callers can move it between threads.
+#[cfg(feature = "tracing")]
+async fn in_span<F: Future>(span: Span, future: F) -> F::Output {
+    tracing::Instrument::instrument(future, span).await
+}
+
+#[cfg(not(feature = "tracing"))]
+async fn in_span<F: Future>(_span: Span, future: F) -> F::Output {
+    future.await
+}
+
+/// A connection to a DFU bootloader via `L`.
+///
+/// See [`BootloaderConnection`](crate::BootloaderConnection), which wraps this for blocking
+/// transports.
+pub(crate) struct Connection<L> {
+    pub(crate) transport: L,
+    /// Encoded request being sent.
+    tx_buf: Vec<u8>,
+    /// Response frame being received. Kept separate from `tx_buf`, so that reading a response
+    /// never clobbers a request that is still in use.
+    rx_buf: Vec<u8>,
+    /// MTU reported by the device.
+    pub(crate) mtu: u16,
+    /// Largest MTU that is used, regardless of what the device reports.
+    pub(crate) max_mtu: u16,
+    /// Packet receipt notification interval (0 = disabled).
+    pub(crate) prn: u16,
+    /// Whether to continue from the data the device already holds, if it is valid.
+    pub(crate) resume: bool,
+    /// Number of times an object is re-sent after a CRC mismatch.
+    pub(crate) chunk_retries: u32,
+    /// Flag that cancels `send_bin` when set.
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+    /// Number of times a timed out read is retried.
+    pub(crate) read_retries: u32,
+    /// Byte that firmware images are padded with.
+    pub(crate) pad_byte: u8,
+    /// Whether firmware images are padded to a multiple of 4 bytes.
+    pub(crate) pad: bool,
+    /// Timeout for executing the last data object of an image.
+    pub(crate) activation_timeout: Option<Duration>,
+    /// Whether a timeout while executing the last data object of an image is an error.
+    pub(crate) require_activation_response: bool,
+    /// Whether the device did not respond to executing the last data object of the current image,
+    /// presumably because it reset to activate the image.
+    pub(crate) reset_on_activation: bool,
+    /// Timeouts for the responses to specific requests.
+    pub(crate) opcode_timeouts: HashMap<OpCode, Duration>,
+    /// Algorithm of the CRCs reported by the device.
+    pub(crate) crc: CrcAlgorithm,
+    /// Number of re-sent objects after which `chunk_size` is reduced (0 = never).
+    pub(crate) mtu_fallback_after: u32,
+    /// Objects re-sent since `chunk_size` was last reduced.
+    failures: u32,
+    /// Size of the data in a write request, if reduced from what the MTU allows.
+    chunk_size: Option<usize>,
+    /// Pause between two successive write requests.
+    pub(crate) inter_packet_delay: Duration,
+}
+
+impl<L: Link> Connection<L> {
+    /// Wraps `transport` without talking to the device, leaving the MTU at 0.
+    pub(crate) fn new(transport: L) -> Self {
+        Self {
+            transport,
+            tx_buf: Vec::new(),
+            rx_buf: Vec::new(),
+            mtu: 0,
+            max_mtu: DEFAULT_MAX_MTU,
+            prn: 0,
+            resume: false,
+            chunk_retries: 0,
+            cancel: None,
+            read_retries: 0,
+            pad_byte: DEFAULT_PAD_BYTE,
+            pad: true,
+            activation_timeout: None,
+            require_activation_response: false,
+            reset_on_activation: false,
+            opcode_timeouts: HashMap::new(),
+            crc: CrcAlgorithm::Crc32,
+            mtu_fallback_after: 0,
+            failures: 0,
+            chunk_size: None,
+            inter_packet_delay: Duration::ZERO,
+        }
+    }
+
+    /// See [`BootloaderConnection::connect`](crate::BootloaderConnection::connect).
+    pub(crate) async fn connect(
+        transport: L,
+        handshake_retries: u32,
+        accepted_versions: &[u8],
+        force_protocol_version: bool,
+    ) -> Result<Self> {
+        in_span(span!("connect"), async {
+            let mut this = Self::new(transport);
+
+            // We must check the protocol version before doing anything else, since any other
+            // command might change if the version changes.
+            let mut attempt = 0;
+            let proto_version = loop {
+                match this.fetch_protocol_version().await {
+                    Err(e @ (DfuError::Serial(_) | DfuError::MalformedResponse(_)))
+                        if attempt < handshake_retries =>
+                    {
+                        attempt += 1;
+                        log::debug!(
+                            "handshake failed ({}), retrying (attempt {} of {})",
+                            e,
+                            attempt,
+                            handshake_retries
+                        );
+                        this.transport.sleep(HANDSHAKE_RETRY_DELAY).await;
+                    }
+                    result => break result?,
+                }
+            };
+            check_protocol_version(proto_version.0, accepted_versions, force_protocol_version)?;
+
+            let mtu = this.fetch_mtu().await?;
+            log::debug!("MTU = {} Bytes", mtu);
+            this.mtu = mtu;
+            this.set_max_mtu(DEFAULT_MAX_MTU)?;
+            Ok(this)
+        })
+        .await
+    }
+
+    /// send `req` and do not fetch any response
+    async fn request<R: Request>(&mut self, req: R) -> Result<()> {
+        self.tx_buf.clear();
+        self.tx_buf.push(R::OPCODE as u8);
+        req.write_payload(&mut self.tx_buf)
+            .map_err(DfuError::Serial)?;
+        log::trace!("--> {:?}", self.tx_buf);
+
+        self.transport
+            .send_frame(&self.tx_buf)
+            .await
+            .map_err(DfuError::Serial)
+    }
+
+    /// send `req` and expect a response.
+    /// aborts if no response is received within timeout window.
+    async fn request_response<R: Request>(&mut self, req: R) -> Result<R::Response> {
+        self.request(req).await?;
+        self.read_response::<R>().await
+    }
+
+    /// wait for the response to a previously sent request of type `R`, using its timeout from
+    /// `opcode_timeouts` if set.
+    async fn read_response<R: Request>(&mut self) -> Result<R::Response> {
+        let timeout = self.opcode_timeouts.get(&R::OPCODE).copied();
+        self.recv_response_within::<R>(timeout).await
+    }
+
+    /// wait for the response to a previously sent request of type `R`, with the transport's
+    /// timeout temporarily changed to `timeout`, if set.
+    async fn recv_response_within<R: Request>(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<R::Response> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.recv_response::<R>().await,
+        };
+        let previous = self.transport.timeout();
+        self.set_timeout(timeout)?;
+        let result = self.recv_response::<R>().await;
+        if let Some(previous) = previous {
+            self.set_timeout(previous)?;
+        }
+        result
+    }
+
+    /// wait for the response to a previously sent request of type `R`, using the current timeout.
+    async fn recv_response<R: Request>(&mut self) -> Result<R::Response> {
+        let mut attempt = 0;
+        loop {
+            self.rx_buf.clear();
+            match self.transport.recv_frame(&mut self.rx_buf).await {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut && attempt < self.read_retries => {
+                    attempt += 1;
+                    let delay = read_retry_delay(attempt);
+                    log::debug!(
+                        "timed out waiting for {:?} response, retrying in {:?} (attempt {} of {})",
+                        R::OPCODE,
+                        delay,
+                        attempt,
+                        self.read_retries
+                    );
+                    self.transport.sleep(delay).await;
+                }
+                result => break result.map_err(DfuError::Serial)?,
+            }
+        }
+        log::trace!("<-- {:?}", self.rx_buf);
+
+        parse_response::<R>(&self.rx_buf)
+    }
+
+    /// See [`BootloaderConnection::set_timeout`](crate::BootloaderConnection::set_timeout).
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.transport
+            .set_timeout(timeout)
+            .map_err(DfuError::Serial)
+    }
+
+    /// See [`BootloaderConnection::set_max_mtu`](crate::BootloaderConnection::set_max_mtu).
+    pub(crate) fn set_max_mtu(&mut self, max_mtu: u16) -> Result<()> {
+        let mtu = self.mtu.min(max_mtu);
+        if self.transport.max_frame_size(mtu) < MIN_FRAME_SIZE {
+            return Err(DfuError::UnsupportedMtu(mtu));
+        }
+        if mtu < self.mtu {
+            log::info!(
+                "Device reports an MTU of {} bytes, limiting it to {} bytes",
+                self.mtu,
+                mtu
+            );
+        }
+        self.max_mtu = max_mtu;
+        Ok(())
+    }
+
+    pub(crate) async fn fetch_protocol_version(&mut self) -> Result<ProtocolVersion> {
+        self.request_response(ProtocolVersionRequest).await
+    }
+
+    /// See [`BootloaderConnection::ping`](crate::BootloaderConnection::ping).
+    pub(crate) async fn ping(&mut self) -> Result<()> {
+        // The ID just needs to differ between runs, so that a stale response is not mistaken for
+        // the one we're waiting for. Every `RandomState` is keyed differently, so hashing nothing
+        // with it yields a fresh pseudo-random value.
+        let id = RandomState::new().build_hasher().finish() as u8;
+
+        let response = self
+            .request_response(PingRequest(id))
+            .await
+            .map_err(|e| DfuError::Ping(e.to_string()))?;
+        if response.id != id {
+            return Err(DfuError::Ping(format!(
+                "sent ID {}, received ID {}",
+                id, response.id
+            )));
+        }
+
+        log::debug!("ping {} ok", id);
+        Ok(())
+    }
+
+    pub(crate) async fn fetch_hardware_version(&mut self) -> Result<HardwareVersion> {
+        self.request_response(HardwareVersionRequest).await
+    }
+
+    pub(crate) async fn fetch_firmware_version(
+        &mut self,
+        image: u8,
+    ) -> Result<FirmwareVersionResponse> {
+        self.request_response(FirmwareVersionRequest(image)).await
+    }
+
+    pub(crate) async fn fetch_firmware_versions(&mut self) -> Result<Vec<FirmwareVersionResponse>> {
+        Ok(self.firmware_info().await?.unwrap_or_default())
+    }
+
+    /// See [`BootloaderConnection::firmware_info`](crate::BootloaderConnection::firmware_info).
+    pub(crate) async fn firmware_info(&mut self) -> Result<Option<Vec<FirmwareVersionResponse>>> {
+        let mut images = Vec::new();
+        for image in 0..=u8::MAX {
+            let response = match self.fetch_firmware_version(image).await {
+                Err(e) if is_unsupported(&e) => {
+                    log::debug!("bootloader does not report firmware versions: {}", e);
+                    if image == 0 {
+                        return Ok(None);
+                    }
+                    break;
+                }
+                response => response?,
+            };
+            if response.fw_type == FirmwareType::Unknown {
+                break;
+            }
+            images.push(response);
+        }
+        Ok(Some(images))
+    }
+
+    /// See [`BootloaderConnection::send_dat`](crate::BootloaderConnection::send_dat).
+    /// modeled after `pc-nrfutil`s `dfu_transport_serial::send_init_packet()`
+    pub(crate) async fn send_dat(&mut self, data: &[u8]) -> Result<()> {
+        in_span(span!("send_dat", bytes = data.len()), async {
+            log::info!("Sending dat file (init packet)...");
+            let select_response = self
+                .select_object_command()
+                .await
+                .with_context(|| "selecting the command object")?;
+            log::debug!("Object selected: {:?}", select_response);
+
+            if select_response.max_size == 0 {
+                return Err(DfuError::MalformedResponse(
+                    "maximum command object size is 0 bytes".to_string(),
+                ));
+            }
+            let max_size = select_response.max_size as usize;
+
+            // Resuming is only supported for init packets that fit into a single object.
+            if self.resume
+                && data.len() <= max_size
+                && self.resume_command(data, &select_response).await?
+            {
+                log::debug!("init packet already present on device");
+                return Ok(());
+            }
+
+            let mut crc = 0;
+            for (index, object) in data.chunks(max_size).enumerate() {
+                let object_size = object.len() as u32;
+                let object_offset = index * max_size;
+                // Like data objects, a corrupted init packet is discarded by creating it again.
+                let object = async {
+                    crc = retry_on_crc_error!(self.chunk_retries, |attempt| async {
+                        if attempt != 0 {
+                            log::warn!(
+                                "CRC mismatch in init packet, retrying (attempt {} of {})",
+                                attempt,
+                                self.chunk_retries
+                            );
+                            self.record_failure();
+                        }
+                        log::debug!("Creating Command...");
+                        self.create_command_object(object_size)
+                            .await
+                            .with_context(|| {
+                                format!("creating command object at offset {}", object_offset)
+                            })?;
+                        log::debug!("Command created");
+
+                        log::debug!("Streaming Data: len: {}", object_size);
+                        self.stream_object_data(object, object_offset, crc)
+                            .await
+                            .with_context(|| {
+                                format!("streaming command object at offset {}", object_offset)
+                            })?;
+
+                        let received_crc = self
+                            .get_crc()
+                            .await
+                            .with_context(|| {
+                                format!("reading CRC of command object at offset {}", object_offset)
+                            })?
+                            .crc;
+                        self.check_crc(object, received_crc, crc)
+                    })
+                    .map_err(|e| locate_crc_error(e, index, object_offset))?;
+
+                    self.execute().await.with_context(|| {
+                        format!("executing command object at offset {}", object_offset)
+                    })
+                };
+                in_span(
+                    span!("command_object", index = index, bytes = object_size),
+                    object,
+                )
+                .await?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Tries to reuse an init packet the device already (partially) holds from a previous run.
+    ///
+    /// Returns `true` if the init packet was completed and executed, `false` if it needs to be
+    /// sent from scratch. Modeled after `pc-nrfutil`s `send_init_packet()`.
+    async fn resume_command(
+        &mut self,
+        data: &[u8],
+        select_response: &SelectResponse,
+    ) -> Result<bool> {
+        let offset = select_response.offset as usize;
+        if offset == 0
+            || offset > data.len()
+            || self.crc.update(0, &data[..offset]) != select_response.crc
+        {
+            return Ok(false);
+        }
+
+        if offset < data.len() {
+            log::debug!(
+                "Streaming remaining init packet data from offset {}",
+                offset
+            );
+            match self
+                .stream_and_check(&data[offset..], offset, select_response.crc)
+                .await
+            {
+                Ok(_) => {}
+                Err(DfuError::Crc { .. } | DfuError::OffsetMismatch { .. }) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.execute().await?;
+        Ok(true)
+    }
+
+    /// Determines where to continue transferring the image read by `objects`, based on the data
+    /// the device already holds from a previous run.
+    ///
+    /// Returns the offset to continue at, which is always at an object boundary, and the CRC of
+    /// the image up to that offset. If the object at that offset had to be read from `objects`
+    /// already, it is returned as well. Modeled after `pc-nrfutil`s `send_firmware()`.
+    async fn resume_data<R: Read>(
+        &mut self,
+        objects: &mut ObjectReader<R>,
+        select_response: &SelectResponse,
+    ) -> Result<(usize, u32, Option<Vec<u8>>)> {
+        let target = select_response.offset as usize;
+        if target == 0 || target > objects.len() {
+            return Ok((0, 0, None));
+        }
+
+        // Skip over the objects the device has received completely, keeping track of their CRC.
+        let mut offset = 0;
+        let mut crc = 0;
+        let object = loop {
+            let object = objects
+                .next_object()?
+                .expect("offset is within the image, so there must be more objects");
+            if offset + object.len() >= target {
+                break object;
+            }
+            crc = self.crc.update(crc, &object);
+            offset += object.len();
+        };
+
+        let received = target - offset;
+        let last = offset + object.len() == objects.len();
+        if self.crc.update(crc, &object[..received]) != select_response.crc {
+            // The data received so far is corrupt. Discard the object it belongs to.
+            return Ok((offset, crc, Some(object)));
+        }
+
+        if received != object.len() {
+            // The last object was only partially transferred, so send the rest of it.
+            let rest = &object[received..];
+            log::debug!("Streaming remaining object data: len: {}", rest.len());
+            match self
+                .stream_and_check(rest, target, select_response.crc)
+                .await
+            {
+                Ok(crc) => {
+                    self.execute_data_object(last).await?;
+                    return Ok((offset + object.len(), crc, None));
+                }
+                Err(DfuError::Crc { .. } | DfuError::OffsetMismatch { .. }) => {
+                    return Ok((offset, crc, Some(object)))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.execute_data_object(last).await?;
+        Ok((target, select_response.crc, None))
+    }
+
+    /// Streams `data` into the current object and checks the CRC the device reports afterwards,
+    /// returning the CRC continued from `initial_crc` over `data`.
+    async fn stream_and_check(
+        &mut self,
+        data: &[u8],
+        initial_offset: usize,
+        initial_crc: u32,
+    ) -> Result<u32> {
+        self.stream_object_data(data, initial_offset, initial_crc)
+            .await?;
+        let received_crc = self.get_crc().await?.crc;
+        self.check_crc(data, received_crc, initial_crc)
+    }
+
+    /// See [`BootloaderConnection::send_bin_from_reader`](crate::BootloaderConnection::send_bin_from_reader).
+    pub(crate) async fn send_bin<P: FnMut(Progress) + ?Sized>(
+        &mut self,
+        reader: impl Read,
+        len: usize,
+        progress: &mut P,
+    ) -> Result<UpdateReport> {
+        in_span(span!("send_bin", bytes = len), async {
+            log::info!("Sending bin (firmware image) of size {}...", len);
+            let start = Instant::now();
+            self.reset_on_activation = false;
+
+            log::debug!("Selecting Object: type Data");
+            let mut select_response = self
+                .select_object_data()
+                .await
+                .with_context(|| "selecting the data object")?;
+            log::debug!("Object selected: {:?}", select_response);
+
+            if select_response.max_size < 4 {
+                return Err(DfuError::MalformedResponse(format!(
+                    "maximum data object size of {} bytes is too small",
+                    select_response.max_size
+                )));
+            }
+            let mut objects = ObjectReader::new(
+                reader,
+                len,
+                select_response.max_size as usize,
+                Some(self.pad_byte).filter(|_| self.pad),
+            );
+            let total = objects.len();
+            let object_size = objects.object_size;
+            log::debug!(
+                "MTU: {} bytes (reported: {}), max chunk size: {} bytes, \
+                 data object max size: {} bytes",
+                self.mtu.min(self.max_mtu),
+                self.mtu,
+                self.chunk_size(),
+                select_response.max_size
+            );
+            if is_stale(&select_response, total, self.resume) {
+                log::warn!(
+                    "Discarding {} bytes of firmware data left on the device by a previous run",
+                    select_response.offset
+                );
+                self.abort()
+                    .await
+                    .with_context(|| "discarding stale firmware data")?;
+                select_response = self
+                    .select_object_data()
+                    .await
+                    .with_context(|| "selecting the data object")?;
+                log::debug!("Object selected: {:?}", select_response);
+            }
+            let (mut offset, mut prev_chunk_crc, mut pending) = if self.resume {
+                self.resume_data(&mut objects, &select_response)
+                    .await
+                    .with_context(|| "resuming the firmware transfer")?
+            } else {
+                (0, 0, None)
+            };
+            let mut bytes_sent: u64 = 0;
+            let mut chunks: u32 = 0;
+            let mut last_throughput_log = Instant::now();
+
+            if offset != 0 {
+                log::info!("Resuming firmware transfer at offset {}", offset);
+                progress(Progress {
+                    sent: offset as u64,
+                    total: total as u64,
+                });
+            }
+
+            while let Some(chunk) = match pending.take() {
+                Some(chunk) => Some(chunk),
+                None => objects.next_object()?,
+            } {
+                if is_cancelled(&self.cancel) {
+                    log::info!("Update cancelled at offset {}", offset);
+                    self.abort().await?;
+                    return Err(DfuError::Cancelled);
+                }
+
+                let curr_chunk_sz: u32 = chunk.len().try_into().unwrap();
+                // Creating the object again discards any data the device received since the last
+                // execute, so a corrupted object can simply be sent again.
+                let data = &chunk[..];
+                let object = async {
+                    prev_chunk_crc = retry_on_crc_error!(self.chunk_retries, |attempt| async {
+                        if attempt != 0 {
+                            log::warn!(
+                                "CRC mismatch at offset {}, retrying object (attempt {} of {})",
+                                offset,
+                                attempt,
+                                self.chunk_retries
+                            );
+                            self.record_failure();
+                        }
+                        self.create_data_object(curr_chunk_sz)
+                            .await
+                            .with_context(|| {
+                                format!("creating data object at offset {}", offset)
+                            })?;
+                        log::debug!("Streaming Data: len: {}", curr_chunk_sz);
+
+                        self.stream_object_data(data, offset, prev_chunk_crc)
+                            .await
+                            .with_context(|| {
+                                format!("streaming data object at offset {}", offset)
+                            })?;
+
+                        let received_crc = self.get_crc().await.with_context(|| {
+                            format!("reading CRC of data object at offset {}", offset)
+                        })?;
+                        log::debug!("crc response: {:?}", received_crc);
+                        self.check_crc(data, received_crc.crc, prev_chunk_crc)
+                    })
+                    .map_err(|e| locate_crc_error(e, offset / object_size, offset))?;
+
+                    self.execute_data_object(offset + chunk.len() == total)
+                        .await
+                        .with_context(|| format!("executing data object at offset {}", offset))
+                };
+                in_span(
+                    span!("data_object", offset = offset, bytes = curr_chunk_sz),
+                    object,
+                )
+                .await?;
+
+                offset += chunk.len();
+                bytes_sent += u64::from(curr_chunk_sz);
+                chunks += 1;
+                progress(Progress {
+                    sent: offset as u64,
+                    total: total as u64,
+                });
+
+                if last_throughput_log.elapsed() >= THROUGHPUT_LOG_INTERVAL {
+                    last_throughput_log = Instant::now();
+                    log::info!(
+                        "{}",
+                        format_throughput(offset, total, bytes_sent, start.elapsed())
+                    );
+                }
+            }
+
+            // The device's running CRC covers the whole image, so this also catches objects that it
+            // dropped after they were confirmed individually.
+            if self.reset_on_activation {
+                log::warn!("Skipping the final CRC check, since the device no longer responds");
+            } else {
+                let final_crc = self
+                    .get_crc()
+                    .await
+                    .with_context(|| "reading CRC of the firmware image")?;
+                log::debug!("final crc response: {:?}", final_crc);
+                check_image_crc(final_crc.crc, prev_chunk_crc)?;
+            }
+
+            log::info!("Done.");
+            Ok(UpdateReport {
+                bytes_sent,
+                chunks,
+                duration: start.elapsed(),
+                final_crc: prev_chunk_crc,
+                skipped: false,
+                images: Vec::new(),
+            })
+        })
+        .await
+    }
+
+    pub(crate) fn check_crc(&self, data: &[u8], received_crc: u32, initial: u32) -> Result<u32> {
+        check_crc(self.crc, data, received_crc, initial)
+    }
+
+    pub(crate) async fn select_object_command(&mut self) -> Result<SelectResponse> {
+        self.request_response(SelectRequest(ObjectType::Command))
+            .await
+    }
+
+    pub(crate) async fn select_object_data(&mut self) -> Result<SelectResponse> {
+        self.request_response(SelectRequest(ObjectType::Data)).await
+    }
+
+    pub(crate) async fn create_command_object(&mut self, size: u32) -> Result<()> {
+        self.request_response(CreateObjectRequest {
+            obj_type: ObjectType::Command,
+            size,
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn create_data_object(&mut self, size: u32) -> Result<()> {
+        // Note: Data objects cannot be created if no init packet has been sent. This results in an
+        // `OperationNotPermitted` error.
+        self.request_response(CreateObjectRequest {
+            obj_type: ObjectType::Data,
+            size,
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn set_receipt_notification(&mut self, every_n_packets: u16) -> Result<()> {
+        self.request_response(SetPrnRequest(every_n_packets))
+            .await?;
+        self.prn = every_n_packets;
+        Ok(())
+    }
+
+    pub(crate) async fn fetch_mtu(&mut self) -> Result<u16> {
+        Ok(self.request_response(GetMtuRequest).await?.0)
+    }
+
+    /// See [`BootloaderConnection::stream_object_data`](crate::BootloaderConnection::stream_object_data).
+    pub(crate) async fn stream_object_data(
+        &mut self,
+        data: &[u8],
+        initial_offset: usize,
+        initial_crc: u32,
+    ) -> Result<()> {
+        let max_chunk_size = self.chunk_size();
+        let mut offset = initial_offset;
+        let mut crc = initial_crc;
+
+        for (i, chunk) in data.chunks(max_chunk_size).enumerate() {
+            if i != 0 && !self.inter_packet_delay.is_zero() {
+                self.transport.sleep(self.inter_packet_delay).await;
+            }
+            self.request(WriteRequest {
+                request_payload: chunk,
+            })
+            .await?;
+            offset += chunk.len();
+            crc = self.crc.update(crc, chunk);
+
+            // The device counts write packets per object, and responds to every `prn`-th one.
+            // Waiting for that receipt before sending more data keeps us from overrunning it.
+            if self.prn != 0 && (i + 1) % usize::from(self.prn) == 0 {
+                // Receipts are sent as responses to a CRC request, with the offset and CRC so far.
+                let receipt = self.read_response::<CrcRequest>().await?;
+                log::trace!("receipt notification: {:?}", receipt);
+                check_receipt(&receipt, offset, crc)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn abort(&mut self) -> Result<()> {
+        self.request(AbortRequest).await
+    }
+
+    /// Returns the size of the data in a single write request.
+    pub(crate) fn chunk_size(&self) -> usize {
+        // On the wire, the write request also contains the opcode byte.
+        let max = self.transport.max_frame_size(self.mtu.min(self.max_mtu)) - 1;
+        self.chunk_size.map_or(max, |size| size.min(max))
+    }
+
+    /// Records that an object had to be re-sent, reducing the size of write requests if this
+    /// happened too often.
+    fn record_failure(&mut self) {
+        if self.mtu_fallback_after == 0 {
+            return;
+        }
+        self.failures += 1;
+        if self.failures < self.mtu_fallback_after {
+            return;
+        }
+        self.failures = 0;
+        let current = self.chunk_size();
+        if let Some(reduced) = reduced_chunk_size(current) {
+            log::warn!(
+                "Reducing write requests from {} to {} bytes after repeated failures",
+                current,
+                reduced
+            );
+            self.chunk_size = Some(reduced);
+        }
+    }
+
+    /// Executes a data object. For the `last` object of an image, this waits for the activation
+    /// timeout instead of the transport's regular one, if set, and tolerates the device resetting
+    /// without a response unless `require_activation_response` is set.
+    async fn execute_data_object(&mut self, last: bool) -> Result<()> {
+        let result = if last {
+            self.execute_last_object().await
+        } else {
+            self.execute().await
+        };
+        match result {
+            Err(e) if last && !self.require_activation_response && is_timeout(&e) => {
+                log::warn!(
+                    "device did not respond to executing the last object, \
+                    assuming it reset to activate the image"
+                );
+                self.reset_on_activation = true;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Executes the last data object of an image, which makes the device validate and activate it.
+    ///
+    /// This can take many seconds, during which the device is silent. The response is therefore
+    /// awaited in slices of `ACTIVATION_HEARTBEAT_INTERVAL`, logging in between to show that the
+    /// update is still alive.
+    async fn execute_last_object(&mut self) -> Result<()> {
+        let timeout = self
+            .activation_timeout
+            .or_else(|| self.opcode_timeouts.get(&OpCode::Execute).copied())
+            .or_else(|| self.transport.timeout());
+        let mut remaining = match timeout {
+            Some(timeout) => timeout,
+            None => return self.execute().await,
+        };
+        self.request(ExecuteRequest).await?;
+        let start = Instant::now();
+        loop {
+            let slice = remaining.min(ACTIVATION_HEARTBEAT_INTERVAL);
+            remaining -= slice;
+            match self
+                .recv_response_within::<ExecuteRequest>(Some(slice))
+                .await
+            {
+                Err(e) if !remaining.is_zero() && is_timeout(&e) => log::info!(
+                    "Waiting for the device to complete activation ({}s)...",
+                    start.elapsed().as_secs()
+                ),
+                result => return result.map(drop),
+            }
+        }
+    }
+
+    pub(crate) async fn get_crc(&mut self) -> Result<CrcResponse> {
+        self.request_response(CrcRequest).await
+    }
+
+    // tell the target to execute whatever request setup we sent them before
+    pub(crate) async fn execute(&mut self) -> Result<()> {
+        self.request_response(ExecuteRequest).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Checksums the bootloader uses to confirm the data it received.
+
+use std::hash::Hasher;
+
+/// Reflected polynomial of CRC-32C (Castagnoli).
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+/// The CRC algorithm a bootloader uses for the checksums it reports.
+///
+/// The CRC always covers all data received for the current object type, continuing from the CRC
+/// of the objects before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CrcAlgorithm {
+    /// CRC-32 (IEEE 802.3), as used by Nordic's bootloader.
+    #[default]
+    Crc32,
+    /// CRC-32C (Castagnoli), used by some bootloader forks.
+    Crc32c,
+}
+
+impl CrcAlgorithm {
+    /// Continues the CRC `crc` of the preceding data over `data`. Pass 0 as `crc` to start a new
+    /// checksum.
+    pub fn update(self, crc: u32, data: &[u8]) -> u32 {
+        match self {
+            CrcAlgorithm::Crc32 => crc32_update(crc, data),
+            CrcAlgorithm::Crc32c => crc32c_update(crc, data),
+        }
+    }
+}
+
+/// Continues the CRC-32 `crc` over `data`.
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut digest = crc32fast::Hasher::new_with_initial(crc);
+    digest.write(data);
+    digest.finalize()
+}
+
+/// Continues the CRC-32C `crc` over `data`.
+///
+/// This is computed bit by bit, which is plenty fast for the few hundred kilobytes of a firmware
+/// image.
+fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (CRC32C_POLY & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        // The standard check value of each algorithm is its CRC of "123456789".
+        assert_eq!(CrcAlgorithm::Crc32.update(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(CrcAlgorithm::Crc32c.update(0, b"123456789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn continuation() {
+        for &algorithm in &[CrcAlgorithm::Crc32, CrcAlgorithm::Crc32c] {
+            let first = algorithm.update(0, b"1234");
+            assert_eq!(
+                algorithm.update(first, b"56789"),
+                algorithm.update(0, b"123456789")
+            );
+        }
+    }
+}
@@ -1,12 +1,11 @@
-use std::iter;
-
 use object::{
     elf::{FileHeader32, PT_LOAD},
     read::elf::{FileHeader, ProgramHeader, SectionHeader},
     Endianness, FileKind,
 };
 
-use crate::Result;
+// ELF loading is not part of the public flashing API yet, so it keeps using ad-hoc errors.
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 pub fn read_elf_image(elf: &[u8]) -> Result<Vec<u8>> {
     struct Chunk<'a> {
@@ -102,7 +101,7 @@ pub fn read_elf_image(elf: &[u8]) -> Result<Vec<u8>> {
 
         // Fill gaps between chunks with 0 bytes.
         let gap = chunk.flash_addr - addr;
-        image.extend(iter::repeat(0).take(gap as usize));
+        image.resize(image.len() + gap as usize, 0);
         if gap > 0 {
             log::debug!("0x{:08x}-0x{:08x} (gap)", addr, addr + gap - 1);
         }
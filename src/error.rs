@@ -0,0 +1,175 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::messages::{describe_error, error_name, ExtError, OpCode, ResultCode};
+use crate::ImageKind;
+
+/// Errors that can occur while reading a DFU package or updating a device.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum DfuError {
+    /// The CRC reported by the device does not match the data that was sent.
+    #[error(
+        "crc failed: expected {expected} - received {received}{}",
+        describe_location(*object, *offset)
+    )]
+    Crc {
+        expected: u32,
+        received: u32,
+        /// Index of the object in which the mismatch was detected, if known.
+        object: Option<u32>,
+        /// Offset of that object in the init packet or firmware image, if known.
+        offset: Option<u32>,
+    },
+
+    /// The device reports having received a different amount of data than was sent, i.e. write
+    /// requests were lost on the way.
+    #[error("offset mismatch: expected {expected} - received {received}")]
+    OffsetMismatch { expected: u32, received: u32 },
+
+    /// Reading from or writing to the serial port (or other [`Transport`](crate::Transport))
+    /// failed. This includes timeouts.
+    #[error("error while communicating over the serial port: {0}")]
+    Serial(io::Error),
+
+    /// The bootloader rejected a request.
+    #[error(
+        "{opcode:?} request failed with {}: {}",
+        error_name(*result_code, *ext_error),
+        describe_error(*result_code, *ext_error)
+    )]
+    Protocol {
+        opcode: OpCode,
+        result_code: ResultCode,
+        /// Only present if `result_code` is [`ResultCode::ExtError`].
+        ext_error: Option<ExtError>,
+    },
+
+    /// The device did not respond to a ping, or echoed the wrong ID.
+    #[error("device not responding to ping ({0})")]
+    Ping(String),
+
+    /// The bootloader sent a response that does not conform to the protocol.
+    #[error("malformed response ({0})")]
+    MalformedResponse(String),
+
+    /// The DFU package could not be read.
+    #[error("failed to read DFU package: {0}")]
+    Package(#[from] zip::result::ZipError),
+
+    /// The DFU package is well-formed, but its contents are not usable.
+    #[error("invalid DFU package: {0}")]
+    InvalidPackage(String),
+
+    /// A firmware image does not have the size or hash declared by its init packet.
+    #[error("firmware image does not match init packet: {0}")]
+    InitPacketMismatch(String),
+
+    /// The DFU package contains an invalid `manifest.json`.
+    #[error("invalid DFU package manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+
+    /// The device reports a different part number than [`UpdateOptions::expected_part`].
+    ///
+    /// [`UpdateOptions::expected_part`]: crate::UpdateOptions::expected_part
+    #[error("device reports part number 0x{actual:x}, expected 0x{expected:x}")]
+    WrongPart { expected: u32, actual: u32 },
+
+    /// The device reports an MTU that is too small to send any requests.
+    #[error("device reports an MTU of {0} bytes, which is too small")]
+    UnsupportedMtu(u16),
+
+    /// Resetting the device into the bootloader via USB failed.
+    #[error("failed to trigger DFU mode: {0}")]
+    Trigger(String),
+
+    /// An image requires a SoftDevice the device lacks, or vice versa, see
+    /// [`UpdateOptions::ignore_sd_req`](crate::UpdateOptions::ignore_sd_req).
+    #[error("incompatible SoftDevice: {0}")]
+    SoftDeviceMismatch(String),
+
+    /// The device speaks a version of the DFU protocol we do not support.
+    #[error("device reports protocol version {got}, we only support {supported}")]
+    UnsupportedProtocolVersion { got: u8, supported: u8 },
+
+    /// The update was cancelled via [`UpdateOptions::cancel`](crate::UpdateOptions::cancel).
+    #[error("update cancelled")]
+    Cancelled,
+
+    /// The device reset to activate the `flashed` image, before the `remaining` images of the
+    /// package could be sent.
+    ///
+    /// The bootloader resets after activating a SoftDevice or bootloader. Once it is back, the
+    /// remaining images can be flashed by connecting again and running the update with
+    /// [`UpdateOptions::only`](crate::UpdateOptions::only) set to them.
+    #[error(
+        "device reset to activate the {flashed} image, reconnect to flash the rest ({})",
+        describe_kinds(remaining)
+    )]
+    ResetAfterActivation {
+        flashed: ImageKind,
+        remaining: Vec<ImageKind>,
+    },
+
+    /// Another error, along with the operation that was in progress when it occurred.
+    ///
+    /// Use [`DfuError::root`] to get at the underlying error.
+    #[error("while {context}: {source}")]
+    Context {
+        /// What was being done, e.g. "creating data object at offset 40960".
+        context: String,
+        source: Box<DfuError>,
+    },
+}
+
+impl DfuError {
+    /// Returns the underlying error, skipping any [`DfuError::Context`] wrapped around it.
+    pub fn root(&self) -> &DfuError {
+        match self {
+            DfuError::Context { source, .. } => source.root(),
+            e => e,
+        }
+    }
+}
+
+/// Adds the operation in progress to errors returned by the DFU steps.
+pub(crate) trait ResultExt<T> {
+    /// Wraps an error in [`DfuError::Context`] describing what was being done.
+    ///
+    /// CRC and offset mismatches are left alone, as they already say where they occurred and are
+    /// retried by matching on them. So is [`DfuError::Cancelled`], which is not a failure.
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, DfuError>;
+}
+
+impl<T> ResultExt<T> for Result<T, DfuError> {
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T, DfuError> {
+        self.map_err(|e| match e {
+            DfuError::Crc { .. } | DfuError::OffsetMismatch { .. } | DfuError::Cancelled => e,
+            e => DfuError::Context {
+                context: context().into(),
+                source: Box::new(e),
+            },
+        })
+    }
+}
+
+fn describe_kinds(kinds: &[ImageKind]) -> String {
+    let kinds: Vec<_> = kinds.iter().map(|kind| kind.to_string()).collect();
+    kinds.join(", ")
+}
+
+fn describe_location(object: Option<u32>, offset: Option<u32>) -> String {
+    match (object, offset) {
+        (Some(object), Some(offset)) => format!(" in object {} at offset {}", object, offset),
+        (None, Some(offset)) => format!(" at offset {}", offset),
+        (Some(object), None) => format!(" in object {}", object),
+        (None, None) => String::new(),
+    }
+}
+
+impl From<serialport::Error> for DfuError {
+    fn from(e: serialport::Error) -> Self {
+        DfuError::Serial(e.into())
+    }
+}
@@ -1,15 +1,22 @@
-//! Implements serialization of "init packets", which contain firmware metadata and precede the
-//! actual firmware upload.
+//! Implements serialization and parsing of "init packets", which contain firmware metadata and
+//! precede the actual firmware upload.
 //!
 //! The full init command format is defined [here][init].
 //!
 //! [init]: https://github.com/tmael/nRF5_SDK/blob/master/components/libraries/bootloader/dfu/dfu-cc.proto
 
-use rohs::WireType;
+use std::fmt;
+use std::io::{self, Read};
+
+use rohs::{FieldValue, MessageReader, WireType};
 use sha2::{Digest, Sha256};
+use zip::result::ZipError;
+
+use crate::{DfuError, ImageKind};
 
-/// Tiny protobuf writer shim, free of `Pb`.
+/// Tiny protobuf reader and writer shim, free of `Pb`.
 mod rohs {
+    use std::convert::TryFrom;
     use std::mem;
 
     pub enum WireType {
@@ -32,7 +39,7 @@ mod rohs {
         fn write(&self, writer: &mut MessageWriter) {
             // Nested messages are prefixed with their encoded length.
 
-            let prev_buf = mem::replace(&mut writer.buf, Vec::new());
+            let prev_buf = mem::take(&mut writer.buf);
             <M as Message>::write(self, writer);
             let message = mem::replace(&mut writer.buf, prev_buf);
             writer.write_varint(message.len() as u64);
@@ -105,9 +112,68 @@ mod rohs {
         message.write(&mut w);
         w.buf
     }
+
+    pub enum FieldValue<'a> {
+        Varint(u64),
+        LengthDelimited(&'a [u8]),
+        /// 32- or 64-bit fixed-width value. We don't use any fields of this type.
+        Fixed,
+    }
+
+    /// Reads the fields of an encoded message one by one.
+    pub struct MessageReader<'a> {
+        buf: &'a [u8],
+    }
+
+    impl<'a> MessageReader<'a> {
+        pub fn new(buf: &'a [u8]) -> Self {
+            Self { buf }
+        }
+
+        /// Returns the next field number and its value, or `None` at the end of the message.
+        pub fn read_field(&mut self) -> Result<Option<(u32, FieldValue<'a>)>, String> {
+            if self.buf.is_empty() {
+                return Ok(None);
+            }
+
+            let key = self.read_varint()?;
+            let field_number = u32::try_from(key >> 3)
+                .map_err(|_| format!("invalid field number {}", key >> 3))?;
+            let value = match key & 0b111 {
+                0 => FieldValue::Varint(self.read_varint()?),
+                1 => {
+                    self.read_bytes(8)?;
+                    FieldValue::Fixed
+                }
+                2 => {
+                    let len = self.read_varint()?;
+                    FieldValue::LengthDelimited(self.read_bytes(len)?)
+                }
+                5 => {
+                    self.read_bytes(4)?;
+                    FieldValue::Fixed
+                }
+                wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+            };
+            Ok(Some((field_number, value)))
+        }
+
+        fn read_varint(&mut self) -> Result<u64, String> {
+            leb128::read::unsigned(&mut self.buf).map_err(|e| format!("invalid varint: {}", e))
+        }
+
+        fn read_bytes(&mut self, len: u64) -> Result<&'a [u8], String> {
+            if len > self.buf.len() as u64 {
+                return Err("message is truncated".to_string());
+            }
+            let (bytes, rest) = self.buf.split_at(len as usize);
+            self.buf = rest;
+            Ok(bytes)
+        }
+    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
 enum FwType {
     Application = 0,
@@ -124,7 +190,7 @@ impl rohs::Value for FwType {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
 enum HashType {
     NoHash = 0,
@@ -135,6 +201,31 @@ enum HashType {
     Sha512 = 4,
 }
 
+impl FwType {
+    fn from_u64(value: u64) -> Option<Self> {
+        Some(match value {
+            0 => FwType::Application,
+            1 => FwType::Softdevice,
+            2 => FwType::Bootloader,
+            3 => FwType::SoftdeviceAndBootloader,
+            _ => return None,
+        })
+    }
+}
+
+impl HashType {
+    fn from_u64(value: u64) -> Option<Self> {
+        Some(match value {
+            0 => HashType::NoHash,
+            1 => HashType::Crc,
+            2 => HashType::Sha128,
+            3 => HashType::Sha256,
+            4 => HashType::Sha512,
+            _ => return None,
+        })
+    }
+}
+
 impl rohs::Value for HashType {
     const TYPE: WireType = WireType::Varint;
 
@@ -161,6 +252,8 @@ struct InitCommand<'a> {
     fw_version: u32,
     /// Marked as optional, but omitting it results in `InitCommandInvalid`.
     hw_version: u32,
+    /// Firmware IDs of the SoftDevices the image works with.
+    sd_req: &'a [u32],
     fw_type: FwType,
     sd_size: u32,
     bl_size: u32,
@@ -175,6 +268,9 @@ impl rohs::Message for InitCommand<'_> {
     fn write(&self, writer: &mut rohs::MessageWriter) {
         writer.write_field("fw_version", 1, &self.fw_version);
         writer.write_field("hw_version", 2, &self.hw_version);
+        for id in self.sd_req {
+            writer.write_field("sd_req", 3, id);
+        }
         writer.write_field("type", 4, &self.fw_type);
         writer.write_field("sd_size", 5, &self.sd_size);
         writer.write_field("bl_size", 6, &self.bl_size);
@@ -216,6 +312,12 @@ impl rohs::Message for Packet<'_> {
 }
 
 pub fn build_init_packet(image: &[u8]) -> Vec<u8> {
+    build_init_packet_with_sd_req(image, &[])
+}
+
+/// Like [`build_init_packet`], but declares that the image requires one of the SoftDevices with
+/// the firmware IDs `sd_req`.
+pub fn build_init_packet_with_sd_req(image: &[u8], sd_req: &[u32]) -> Vec<u8> {
     let mut hash = {
         let mut hasher = Sha256::new();
         hasher.update(image);
@@ -244,6 +346,7 @@ pub fn build_init_packet(image: &[u8]) -> Vec<u8> {
         // 52 is the default, the docs do not recommend using it, but it's unclear how to
         // accomplish that.
         hw_version: 52,
+        sd_req,
         fw_type: FwType::Application,
         sd_size: 0,
         bl_size: 0,
@@ -258,6 +361,249 @@ pub fn build_init_packet(image: &[u8]) -> Vec<u8> {
     rohs::encode_message(&packet)
 }
 
+/// The parts of an init command that describe the firmware image it precedes.
+#[derive(Debug)]
+struct ImageInfo {
+    fw_version: u32,
+    hw_version: Option<u32>,
+    sd_req: Vec<u32>,
+    fw_type: FwType,
+    sd_size: u32,
+    bl_size: u32,
+    app_size: u32,
+    /// Hash type and hash, in the byte order used on the wire.
+    hash: Option<(HashType, Vec<u8>)>,
+}
+
+impl ImageInfo {
+    /// Returns the size of the firmware image, as declared by the init command.
+    fn firmware_size(&self) -> u64 {
+        let (sd_size, bl_size, app_size) = (
+            u64::from(self.sd_size),
+            u64::from(self.bl_size),
+            u64::from(self.app_size),
+        );
+        match self.fw_type {
+            FwType::Application => app_size,
+            FwType::Softdevice => sd_size,
+            FwType::Bootloader => bl_size,
+            FwType::SoftdeviceAndBootloader => sd_size + bl_size,
+        }
+    }
+}
+
+/// Extracts the firmware metadata from an (optionally signed) init packet.
+fn parse_init_packet(packet: &[u8]) -> Result<ImageInfo, String> {
+    // Packet { command = 1, signed_command = 2 }, SignedCommand { command = 1, ... }
+    let command = find_message(packet, 1)?
+        .or(match find_message(packet, 2)? {
+            Some(signed_command) => find_message(signed_command, 1)?,
+            None => None,
+        })
+        .ok_or("init packet does not contain a command")?;
+    // Command { op_code = 1, init = 2 }
+    let init = find_message(command, 2)?.ok_or("command is not an init command")?;
+
+    let mut info = ImageInfo {
+        fw_version: 0,
+        hw_version: None,
+        sd_req: Vec::new(),
+        fw_type: FwType::Application,
+        sd_size: 0,
+        bl_size: 0,
+        app_size: 0,
+        hash: None,
+    };
+    let mut reader = MessageReader::new(init);
+    while let Some((field_number, value)) = reader.read_field()? {
+        match (field_number, value) {
+            (1, FieldValue::Varint(version)) => info.fw_version = version as u32,
+            (2, FieldValue::Varint(version)) => info.hw_version = Some(version as u32),
+            (3, FieldValue::Varint(id)) => info.sd_req.push(id as u32),
+            // Encoders may pack repeated fields into a single run of varints.
+            (3, FieldValue::LengthDelimited(mut ids)) => {
+                while !ids.is_empty() {
+                    let id = leb128::read::unsigned(&mut ids)
+                        .map_err(|e| format!("invalid sd_req: {}", e))?;
+                    info.sd_req.push(id as u32);
+                }
+            }
+            (4, FieldValue::Varint(ty)) => {
+                info.fw_type =
+                    FwType::from_u64(ty).ok_or_else(|| format!("unknown firmware type {}", ty))?;
+            }
+            (5, FieldValue::Varint(size)) => info.sd_size = size as u32,
+            (6, FieldValue::Varint(size)) => info.bl_size = size as u32,
+            (7, FieldValue::Varint(size)) => info.app_size = size as u32,
+            (8, FieldValue::LengthDelimited(hash)) => info.hash = Some(parse_hash(hash)?),
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+fn parse_hash(message: &[u8]) -> Result<(HashType, Vec<u8>), String> {
+    let mut hash_type = HashType::NoHash;
+    let mut hash = Vec::new();
+    let mut reader = MessageReader::new(message);
+    while let Some((field_number, value)) = reader.read_field()? {
+        match (field_number, value) {
+            (1, FieldValue::Varint(ty)) => {
+                hash_type =
+                    HashType::from_u64(ty).ok_or_else(|| format!("unknown hash type {}", ty))?;
+            }
+            (2, FieldValue::LengthDelimited(bytes)) => hash = bytes.to_vec(),
+            _ => {}
+        }
+    }
+    Ok((hash_type, hash))
+}
+
+/// Returns the contents of the last embedded message with the given field number.
+fn find_message(message: &[u8], field_number: u32) -> Result<Option<&[u8]>, String> {
+    let mut found = None;
+    let mut reader = MessageReader::new(message);
+    while let Some((number, value)) = reader.read_field()? {
+        if let (true, FieldValue::LengthDelimited(bytes)) = (number == field_number, value) {
+            found = Some(bytes);
+        }
+    }
+    Ok(found)
+}
+
+/// Checks that the firmware image read from `image` has the size and hash declared by the init
+/// packet `packet`.
+///
+/// The image is hashed while it is read, so it never has to be held in memory as a whole. Hash
+/// types other than SHA-256 and CRC32 are not checked.
+pub fn verify_image(packet: &[u8], mut image: impl Read) -> crate::Result<()> {
+    let info = parse(packet)?;
+    log::debug!("init packet: {:?}", info);
+
+    let mut sha256 = Sha256::new();
+    let mut crc = crc32fast::Hasher::new();
+    let mut len: u64 = 0;
+    let mut buf = [0; 4096];
+    loop {
+        let n = match image.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ZipError::Io(e).into()),
+        };
+        sha256.update(&buf[..n]);
+        crc.update(&buf[..n]);
+        len += n as u64;
+    }
+
+    let mismatch = |what: String| Err(DfuError::InitPacketMismatch(what));
+
+    if info.firmware_size() != len {
+        // A short image is most likely truncated, e.g. by an interrupted download or copy.
+        let hint = if len < info.firmware_size() {
+            format!(
+                " ({} bytes missing, the image may be truncated)",
+                info.firmware_size() - len
+            )
+        } else {
+            String::new()
+        };
+        return mismatch(format!(
+            "init packet declares {} bytes, image has {} bytes{}",
+            info.firmware_size(),
+            len,
+            hint
+        ));
+    }
+
+    match &info.hash {
+        Some((HashType::Sha256, hash)) => {
+            let mut actual = sha256.finalize().to_vec();
+            // Little-endian, see `build_init_packet`.
+            actual.reverse();
+            if *hash != actual {
+                return mismatch("SHA-256 hash differs".to_string());
+            }
+        }
+        Some((HashType::Crc, hash)) => {
+            if *hash != crc.finalize().to_le_bytes() {
+                return mismatch("CRC differs".to_string());
+            }
+        }
+        Some((HashType::NoHash, _)) | None => {}
+        Some((hash_type, _)) => {
+            log::warn!(
+                "cannot verify firmware image with hash type {:?}",
+                hash_type
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the kind of firmware image the init packet `packet` precedes.
+pub fn image_kind(packet: &[u8]) -> crate::Result<ImageKind> {
+    Ok(match parse(packet)?.fw_type {
+        FwType::Application => ImageKind::Application,
+        FwType::Softdevice => ImageKind::Softdevice,
+        FwType::Bootloader => ImageKind::Bootloader,
+        FwType::SoftdeviceAndBootloader => ImageKind::SoftdeviceBootloader,
+    })
+}
+
+/// Firmware metadata declared by an init packet, as returned by [`describe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitPacketInfo {
+    /// Version of the firmware image (`fw_version`).
+    pub fw_version: u32,
+    /// Hardware version the image is built for (`hw_version`), if declared.
+    pub hw_version: Option<u32>,
+    /// Firmware IDs of the SoftDevices the image is compatible with (`sd_req`).
+    pub sd_req: Vec<u32>,
+    /// Size of the firmware image in bytes.
+    pub image_size: u64,
+}
+
+impl fmt::Display for InitPacketInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "firmware version {}", self.fw_version)?;
+        if let Some(version) = self.hw_version {
+            write!(f, ", hardware version {}", version)?;
+        }
+        if !self.sd_req.is_empty() {
+            let ids = self
+                .sd_req
+                .iter()
+                .map(|id| format!("0x{:04x}", id))
+                .collect::<Vec<_>>();
+            write!(f, ", SoftDevice {}", ids.join("/"))?;
+        }
+        write!(f, ", {} bytes", self.image_size)
+    }
+}
+
+/// Returns the firmware metadata declared by the init packet `packet`.
+pub fn describe(packet: &[u8]) -> crate::Result<InitPacketInfo> {
+    let info = parse(packet)?;
+    Ok(InitPacketInfo {
+        fw_version: info.fw_version,
+        hw_version: info.hw_version,
+        image_size: info.firmware_size(),
+        sd_req: info.sd_req,
+    })
+}
+
+/// Returns the firmware version declared by the init packet `packet`.
+pub fn firmware_version(packet: &[u8]) -> crate::Result<u32> {
+    Ok(parse(packet)?.fw_version)
+}
+
+fn parse(packet: &[u8]) -> crate::Result<ImageInfo> {
+    parse_init_packet(packet)
+        .map_err(|e| DfuError::InvalidPackage(format!("failed to parse init packet: {}", e)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -282,6 +628,7 @@ mod tests {
             Packet::Command(Command::InitCommand(InitCommand {
                 fw_version: 0,
                 hw_version: 52,
+                sd_req: &[],
                 fw_type: FwType::Application,
                 sd_size: 0,
                 bl_size: 0,
@@ -301,4 +648,156 @@ mod tests {
             ]],
         );
     }
+
+    /// Test-only wrapper, since we never need to sign init packets ourselves.
+    struct SignedPacket<'a>(Command<'a>);
+
+    impl rohs::Message for SignedPacket<'_> {
+        fn write(&self, writer: &mut rohs::MessageWriter) {
+            struct SignedCommand<'a, 'b>(&'b Command<'a>);
+
+            impl rohs::Message for SignedCommand<'_, '_> {
+                fn write(&self, writer: &mut rohs::MessageWriter) {
+                    writer.write_field("command", 1, self.0);
+                    writer.write_field("signature_type", 2, &0);
+                    writer.write_field("signature", 3, &[0x5a; 64][..]);
+                }
+            }
+
+            writer.write_field("signed_command", 2, &SignedCommand(&self.0));
+        }
+    }
+
+    #[test]
+    fn verify_matching_image() {
+        let image = [0x12, 0x34, 0x56, 0x78, 0x9a];
+        let packet = build_init_packet(&image);
+        verify_image(&packet, &image[..]).unwrap();
+    }
+
+    #[test]
+    fn verify_mismatched_hash() {
+        let packet = build_init_packet(&[1, 2, 3, 4]);
+        let err = verify_image(&packet, &[1, 2, 3, 5][..]).unwrap_err();
+        assert!(matches!(err, DfuError::InitPacketMismatch(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn verify_mismatched_size() {
+        let packet = build_init_packet(&[1, 2, 3, 4]);
+        let err = verify_image(&packet, &[1, 2, 3, 4, 5][..]).unwrap_err();
+        assert!(matches!(err, DfuError::InitPacketMismatch(_)), "{:?}", err);
+
+        let err = verify_image(&packet, &[1, 2][..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "firmware image does not match init packet: init packet declares 4 bytes, image has 2 \
+             bytes (2 bytes missing, the image may be truncated)"
+        );
+    }
+
+    #[test]
+    fn verify_signed_crc() {
+        let image = [0xff; 16];
+        let crc = crc32fast::hash(&image).to_le_bytes();
+        let packet = rohs::encode_message(&SignedPacket(Command::InitCommand(InitCommand {
+            fw_version: 7,
+            hw_version: 52,
+            sd_req: &[],
+            fw_type: FwType::Bootloader,
+            sd_size: 0,
+            bl_size: 16,
+            app_size: 0,
+            hash: Hash {
+                hash_type: HashType::Crc,
+                hash: &crc,
+            },
+            is_debug: None,
+        })));
+
+        verify_image(&packet, &image[..]).unwrap();
+        let err = verify_image(&packet, &[0; 16][..]).unwrap_err();
+        assert!(matches!(err, DfuError::InitPacketMismatch(_)), "{:?}", err);
+        assert_eq!(image_kind(&packet).unwrap(), ImageKind::Bootloader);
+        assert_eq!(firmware_version(&packet).unwrap(), 7);
+    }
+
+    #[test]
+    fn describe_packet() {
+        let image = [0x12, 0x34, 0x56, 0x78, 0x9a];
+        let info = describe(&build_init_packet(&image)).unwrap();
+        assert_eq!(
+            info,
+            InitPacketInfo {
+                fw_version: 0,
+                hw_version: Some(52),
+                sd_req: Vec::new(),
+                image_size: 5,
+            }
+        );
+        assert_eq!(
+            info.to_string(),
+            "firmware version 0, hardware version 52, 5 bytes"
+        );
+    }
+
+    #[test]
+    fn describe_sd_req() {
+        /// An init command listing SoftDevices, either as repeated or as packed field.
+        struct SdReqInit(bool);
+
+        impl rohs::Message for SdReqInit {
+            fn write(&self, writer: &mut rohs::MessageWriter) {
+                writer.write_field("fw_version", 1, &3);
+                if self.0 {
+                    // 0x0100 and 0x0101 as varints
+                    writer.write_field("sd_req", 3, &[0x80, 0x02, 0x81, 0x02][..]);
+                } else {
+                    writer.write_field("sd_req", 3, &0x0100);
+                    writer.write_field("sd_req", 3, &0x0101);
+                }
+                writer.write_field("app_size", 7, &1234);
+            }
+        }
+
+        struct InitPacket(SdReqInit);
+
+        impl rohs::Message for InitPacket {
+            fn write(&self, writer: &mut rohs::MessageWriter) {
+                struct Command<'a>(&'a SdReqInit);
+
+                impl rohs::Message for Command<'_> {
+                    fn write(&self, writer: &mut rohs::MessageWriter) {
+                        writer.write_field("op_code", 1, &1);
+                        writer.write_field("init", 2, self.0);
+                    }
+                }
+
+                writer.write_field("command", 1, &Command(&self.0));
+            }
+        }
+
+        for &packed in &[false, true] {
+            let info = describe(&rohs::encode_message(&InitPacket(SdReqInit(packed)))).unwrap();
+            assert_eq!(
+                info,
+                InitPacketInfo {
+                    fw_version: 3,
+                    hw_version: None,
+                    sd_req: vec![0x0100, 0x0101],
+                    image_size: 1234,
+                }
+            );
+            assert_eq!(
+                info.to_string(),
+                "firmware version 3, SoftDevice 0x0100/0x0101, 1234 bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn reject_garbage() {
+        let err = verify_image(&[0xff, 0xff], &[][..]).unwrap_err();
+        assert!(matches!(err, DfuError::InvalidPackage(_)), "{:?}", err);
+    }
 }
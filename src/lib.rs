@@ -0,0 +1,3025 @@
+use serialport::SerialPort;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Seek};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Deserialize;
+use zip::result::ZipError;
+
+#[macro_use]
+mod macros;
+#[cfg(feature = "async")]
+mod asynchronous;
+mod builder;
+mod connection;
+mod crc;
+mod error;
+// Flashing ELF files directly is not wired up yet, only DFU .zip packages are supported.
+#[allow(dead_code)]
+mod elf;
+// Building init packets is only needed for ELF files, which are not supported yet.
+#[allow(dead_code)]
+mod init_packet;
+// Some messages and response fields are part of the protocol but not used yet.
+#[allow(dead_code)]
+mod messages;
+#[cfg(test)]
+mod mock_device;
+pub mod slip;
+mod transport;
+#[cfg(feature = "trigger")]
+mod trigger;
+mod zip_file;
+
+#[cfg(feature = "async")]
+pub use asynchronous::{run_async, AsyncSerialTransport};
+pub use builder::{DfuUpdate, DfuUpdateBuilder};
+pub use crc::CrcAlgorithm;
+pub use error::DfuError;
+pub use init_packet::InitPacketInfo;
+pub use messages::{
+    CrcResponse, ExtError, FirmwareType, FirmwareVersionResponse, HardwareVersion, OpCode,
+    ProtocolVersion, ResultCode, SelectResponse,
+};
+pub use transport::{SerialTransport, TcpTransport, Transport};
+#[cfg(feature = "trigger")]
+pub use trigger::trigger_dfu;
+pub use zip_file::ImageKind;
+
+use connection::{block_on, Connection, Link};
+use zip_file::{DfuPackage, FirmwareImage};
+
+pub type Result<T> = std::result::Result<T, DfuError>;
+
+/// Former name of [`HardwareVersion`].
+#[deprecated(note = "renamed to `HardwareVersion`")]
+pub type HardwareVersionResponse = HardwareVersion;
+
+/// Nordic bootloader protocol version supported by this utility.
+const PROTOCOL_VERSION: u8 = 1;
+
+/// Smallest request frame size (including the opcode) we can work with.
+///
+/// This is the size of a Create request, the largest request that cannot be split up.
+const MIN_FRAME_SIZE: usize = 6;
+
+/// Default for [`UpdateOptions::max_mtu`].
+const DEFAULT_MAX_MTU: u16 = 2048;
+
+/// Smallest write request payload that [`UpdateOptions::mtu_fallback_after`] reduces the size of
+/// write requests to.
+const MIN_FALLBACK_CHUNK_SIZE: usize = 32;
+
+/// How often the transfer speed is logged while sending a firmware image.
+const THROUGHPUT_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often progress is logged while waiting for the device to activate an image.
+const ACTIVATION_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Default for [`UpdateOptions::handshake_retries`].
+const DEFAULT_HANDSHAKE_RETRIES: u32 = 5;
+
+/// Default for [`UpdateOptions::pad_byte`], the erase value of the nRF's internal flash.
+const DEFAULT_PAD_BYTE: u8 = 0xFF;
+
+/// Default for [`UpdateOptions::manifest_name`], as used by `nrfutil`.
+const DEFAULT_MANIFEST_NAME: &str = "manifest.json";
+
+/// Delay between attempts to establish a connection with the bootloader.
+const HANDSHAKE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Delay before the first retry of a timed out read, see [`UpdateOptions::read_retries`]. It
+/// doubles with every further retry.
+const READ_RETRY_BASE_DELAY: Duration = Duration::from_millis(20);
+
+/// Statistics about a completed firmware update.
+///
+/// If the DFU package contains multiple images, the statistics are summed up over all of them.
+#[derive(Debug, Clone)]
+pub struct UpdateReport {
+    /// Number of firmware image bytes transferred (including padding).
+    pub bytes_sent: u64,
+    /// Number of data objects the firmware image was split into.
+    pub chunks: u32,
+    /// Time taken to transfer the firmware image.
+    pub duration: Duration,
+    /// CRC32 of the complete (last) firmware image, as confirmed by the device.
+    pub final_crc: u32,
+    /// Whether the update was skipped because the device already runs the firmware, see
+    /// [`UpdateOptions::skip_if_current`].
+    pub skipped: bool,
+    /// The images in the DFU package, as logged before the transfer. Empty for the report of a
+    /// single image, as returned by [`BootloaderConnection::send_bin`].
+    pub images: Vec<ImageSummary>,
+}
+
+/// Progress of a firmware update, as passed to the callback of [`run_with_progress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Number of firmware image bytes transferred so far.
+    pub sent: u64,
+    /// Total size of the (padded) firmware images in bytes.
+    pub total: u64,
+}
+
+/// Description of a firmware image in a DFU package, as returned by [`check_package`].
+#[derive(Debug, Clone)]
+pub struct ImageSummary {
+    pub kind: ImageKind,
+    /// Name of the init packet file in the package.
+    pub dat_file: String,
+    /// Size of the init packet in bytes.
+    pub dat_len: usize,
+    /// Name of the firmware image file in the package.
+    pub bin_file: String,
+    /// Size of the firmware image in bytes.
+    pub bin_len: usize,
+    /// Size of the firmware image after padding it to a multiple of 4 bytes.
+    pub padded_len: usize,
+    /// CRC32 of the firmware image (without padding).
+    pub bin_crc: u32,
+    /// Metadata declared for this image in the manifest, if any.
+    pub init_packet_data: Option<InitPacketData>,
+    /// Metadata declared by the init packet, which is what the bootloader goes by.
+    pub init_packet: InitPacketInfo,
+}
+
+/// Contents of a DFU package, as returned by [`inspect_package`].
+#[derive(Debug, Clone)]
+pub struct PackageInfo {
+    /// Name of the manifest in the package.
+    pub manifest_file: String,
+    /// The `dfu_version` declared by the manifest, if any.
+    pub dfu_version: Option<f64>,
+    /// The images in the order in which they are flashed.
+    pub images: Vec<ImageSummary>,
+}
+
+/// How the data a device holds for one object type compares to an image, as part of a
+/// [`VerifyReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectMatch {
+    /// Number of bytes the device holds.
+    pub offset: u32,
+    /// Size of the data in the package (for firmware images, including padding).
+    pub len: u32,
+    /// Whether the CRC reported by the device matches the first `offset` bytes of the data.
+    pub crc_matches: bool,
+}
+
+impl ObjectMatch {
+    /// Returns whether the device holds all of the data.
+    pub fn is_complete(&self) -> bool {
+        self.crc_matches && self.offset == self.len
+    }
+}
+
+/// Comparison of an image in a DFU package with the data a device holds, as returned by
+/// [`verify`].
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub kind: ImageKind,
+    /// The init packet, compared with the command object.
+    pub init_packet: ObjectMatch,
+    /// The firmware image, compared with the data objects.
+    pub firmware: ObjectMatch,
+}
+
+/// Options controlling how a firmware update is performed.
+#[derive(Debug, Clone)]
+pub struct UpdateOptions {
+    /// Number of write packets after which the device has to confirm the received data with a
+    /// packet receipt notification (PRN).
+    ///
+    /// This throttles the transfer, which can help on links where the device's receive buffer
+    /// overflows. 0 (the default) disables receipt notifications.
+    pub prn: u16,
+    /// Whether to resume an interrupted update of the same firmware, instead of starting over.
+    ///
+    /// Data that the device already received is only reused if its CRC matches. Enabled by
+    /// default.
+    pub resume: bool,
+    /// How often an object (the init packet or a firmware data object) is re-sent after its CRC
+    /// check failed, before giving up.
+    ///
+    /// Defaults to 3.
+    pub chunk_retries: u32,
+    /// Part number the device has to report (e.g. `0x52840`), checked before anything is flashed.
+    ///
+    /// This guards against flashing firmware onto the wrong chip. `None` (the default) disables
+    /// the check.
+    pub expected_part: Option<u32>,
+    /// How often the initial handshake is retried if the bootloader does not respond properly.
+    ///
+    /// This handles devices whose serial port shows up slightly before the bootloader is ready.
+    /// Defaults to 5.
+    pub handshake_retries: u32,
+    /// Whether to reject DFU packages whose manifest declares a `dfu_version`.
+    ///
+    /// Packages built by `nrfutil` for the Secure DFU bootloader don't declare a version, so this
+    /// crate has not been validated against any package that does (legacy packages, for example,
+    /// declare version 0.5 and target an incompatible bootloader). By default, such packages are
+    /// only warned about.
+    pub strict_dfu_version: bool,
+    /// Whether to flash images whose SoftDevice requirements (`sd_req` in the init packet) the
+    /// device does not meet, only warning about them.
+    ///
+    /// Before flashing a package without a SoftDevice, the firmware installed on the device is
+    /// checked against the `sd_req` of its images, so that an application requiring a SoftDevice is
+    /// not flashed onto a device without one (and vice versa). The bootloader only reports the
+    /// version of its SoftDevice, not the firmware ID listed in `sd_req`, so the ID itself is left
+    /// to the bootloader to check. By default, a mismatch fails the update with
+    /// [`DfuError::SoftDeviceMismatch`].
+    pub ignore_sd_req: bool,
+    /// Flag that cancels the update when set, e.g. from another thread.
+    ///
+    /// The flag is checked before every firmware data object. On cancellation, the transfer is
+    /// aborted and [`DfuError::Cancelled`] is returned. Objects that were already executed are
+    /// kept by the device, so a later update can resume from there.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Whether to skip the update if the device already runs the firmware in the package.
+    ///
+    /// The firmware versions reported by the bootloader are compared against the `fw_version`
+    /// declared by each init packet. If the bootloader does not support reporting its firmware
+    /// versions, the update is always performed. Disabled by default.
+    pub skip_if_current: bool,
+    /// Whether to abort any transfer the device has pending before starting the update.
+    ///
+    /// This recovers bootloaders left in a partial state by a failed run, which otherwise reject
+    /// new objects until they are power cycled. Data left on the device is discarded, so the update
+    /// is never resumed. Disabled by default.
+    pub reset_first: bool,
+    /// How often reading a response is retried after it timed out, before giving up.
+    ///
+    /// This rides out transient hiccups of the USB connection. The delay between retries grows
+    /// exponentially. Other errors, such as malformed responses, are never retried. Defaults to 0.
+    pub read_retries: u32,
+    /// Protocol versions to accept in addition to the supported one.
+    ///
+    /// Some forks of the Nordic bootloader report a different version without changing the
+    /// protocol in any way that matters here. Talking to them is at your own risk, and a warning is
+    /// logged when doing so. Empty by default.
+    pub accepted_protocol_versions: Vec<u8>,
+    /// Whether to talk to bootloaders reporting any protocol version, at your own risk.
+    ///
+    /// Like listing every version in
+    /// [`accepted_protocol_versions`](Self::accepted_protocol_versions): a mismatch only logs a
+    /// warning. If the protocol actually differs, the update may fail halfway and leave the device
+    /// without working firmware. Disabled by default.
+    pub force_protocol_version: bool,
+    /// Byte that firmware images are padded with to a multiple of 4 bytes.
+    ///
+    /// This should be the erase value of the memory the image is written to. Defaults to 0xFF,
+    /// which is correct for the internal flash; external memory may need a different value.
+    pub pad_byte: u8,
+    /// Whether firmware images are padded to a multiple of 4 bytes. Enabled by default.
+    ///
+    /// Nordic's bootloader requires this. Bootloaders that handle unaligned images can be sent the
+    /// exact bytes of the image instead, which matters if it ends in a checksum that the device
+    /// expects at a specific offset.
+    pub pad: bool,
+    /// How long to wait for the device to respond to the execute request of the last data object
+    /// of each image.
+    ///
+    /// Executing the last object makes the device validate and activate the image, which can take
+    /// much longer than any other request (especially for a SoftDevice). If `None` (the default),
+    /// the regular timeout of the serial port applies.
+    ///
+    /// While waiting, a message is logged at the `info` level every 5 seconds.
+    pub activation_timeout: Option<Duration>,
+    /// Fail if the device does not respond to the execute request of the last data object of an
+    /// image.
+    ///
+    /// Some devices reset as soon as they activate an image, without responding. By default
+    /// (`false`), a timeout at this point is therefore treated as success, with a warning, and
+    /// the final CRC check of the image is skipped. Protocol errors always fail the update.
+    pub require_activation_response: bool,
+    /// Halve the size of write requests after this many objects had to be re-sent because of a
+    /// CRC mismatch (0 = never, the default).
+    ///
+    /// Some devices report a larger MTU than their USB stack can reliably handle. Smaller write
+    /// requests are slower, but get the update through. The size is reduced repeatedly, down to
+    /// 32 bytes.
+    pub mtu_fallback_after: u32,
+    /// Largest MTU that is used, even if the device reports a larger one (2048 bytes by default).
+    ///
+    /// Buggy firmware may report an absurdly large MTU, and even a genuine one can exceed what
+    /// the host's USB driver manages to send in a single frame.
+    pub max_mtu: u16,
+    /// Pause between two successive write requests of an object (zero, the default, for none).
+    ///
+    /// Some underpowered devices drop bytes when write requests arrive back-to-back. Pausing
+    /// between them is crude flow control for when packet receipt notifications (`prn`) don't
+    /// help.
+    pub inter_packet_delay: Duration,
+    /// Read and write timeout of the connection to the device.
+    ///
+    /// If set, this is applied before connecting and overrides whatever timeout the serial port
+    /// was opened with. If `None` (the default), the port's timeout is left unchanged.
+    pub timeout: Option<Duration>,
+    /// How long to wait for the responses to individual requests, overriding the regular timeout.
+    ///
+    /// This lets quick requests like [`OpCode::MtuGet`] fail fast, while giving slow ones like
+    /// [`OpCode::Execute`] (which writes to flash) more time. `activation_timeout` takes precedence
+    /// for executing the last data object of an image. Empty by default.
+    pub opcode_timeouts: HashMap<OpCode, Duration>,
+    /// CRC algorithm the bootloader uses for the checksums it reports.
+    ///
+    /// Only needs to be changed for bootloader forks that don't use CRC-32 (the default).
+    pub crc_algorithm: CrcAlgorithm,
+    /// Name of the manifest in DFU packages.
+    ///
+    /// If the package contains no file of this name, the first file whose name ends in
+    /// `manifest.json` is used instead, which covers packagers emitting e.g. `dfu_manifest.json`
+    /// or placing the package contents in a directory. Defaults to `manifest.json`.
+    pub manifest_name: String,
+    /// Kinds of images to flash from the package, skipping all others.
+    ///
+    /// This allows e.g. flashing only the application from a package that also contains a
+    /// SoftDevice the device already runs. Every kind listed must be present in the package. Empty
+    /// (the default) flashes all images.
+    pub only: Vec<ImageKind>,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            prn: 0,
+            resume: true,
+            chunk_retries: 3,
+            expected_part: None,
+            handshake_retries: DEFAULT_HANDSHAKE_RETRIES,
+            strict_dfu_version: false,
+            ignore_sd_req: false,
+            cancel: None,
+            skip_if_current: false,
+            reset_first: false,
+            read_retries: 0,
+            accepted_protocol_versions: Vec::new(),
+            force_protocol_version: false,
+            pad_byte: DEFAULT_PAD_BYTE,
+            pad: true,
+            activation_timeout: None,
+            require_activation_response: false,
+            mtu_fallback_after: 0,
+            max_mtu: DEFAULT_MAX_MTU,
+            inter_packet_delay: Duration::ZERO,
+            timeout: None,
+            opcode_timeouts: HashMap::new(),
+            crc_algorithm: CrcAlgorithm::Crc32,
+            manifest_name: DEFAULT_MANIFEST_NAME.to_string(),
+            only: Vec::new(),
+        }
+    }
+}
+
+/// Flashes the DFU package at `zip_path` onto the bootloader connected via `port`.
+///
+/// All images contained in the package are flashed, SoftDevice and bootloader before the
+/// application.
+///
+/// Diagnostics are emitted through the `log` crate; it is up to the caller to install a logger.
+pub fn run(
+    port: Box<dyn SerialPort>,
+    zip_path: &str,
+    options: &UpdateOptions,
+) -> Result<UpdateReport> {
+    run_with_progress(port, zip_path, options, |_| {})
+}
+
+/// Reads and validates the DFU package at `zip_path` without flashing it.
+///
+/// This performs all checks that [`run`] does before talking to the device, and describes the
+/// images contained in the package. Of the `options`, only those affecting how the package is
+/// validated are used.
+pub fn check_package(zip_path: &str, options: &UpdateOptions) -> Result<Vec<ImageSummary>> {
+    let mut package =
+        zip_file::read_zip_file(zip_path, options.strict_dfu_version, &options.manifest_name)?;
+    select_images(&mut package, &options.only)?;
+    summarize(package)
+}
+
+/// Reads and validates the DFU package at `zip_path`, and describes its manifest and images.
+///
+/// This is the programmatic counterpart of `nrfdfu --dry-run`: it performs the same checks as
+/// [`check_package`] with the default [`UpdateOptions`], without requiring a device.
+pub fn inspect_package(zip_path: &str) -> Result<PackageInfo> {
+    let mut package = zip_file::read_zip_file(zip_path, false, DEFAULT_MANIFEST_NAME)?;
+    let manifest_file = package
+        .manifest_file
+        .take()
+        .expect("packages read from a .zip file have a manifest");
+    let dfu_version = package.dfu_version;
+    Ok(PackageInfo {
+        manifest_file,
+        dfu_version,
+        images: summarize(package)?,
+    })
+}
+
+/// Like [`check_package`], but reads the loose init packet at `dat_path` and firmware image at
+/// `bin_path` instead of a DFU package. See [`run_with_files`].
+pub fn check_files(dat_path: &str, bin_path: &str) -> Result<Vec<ImageSummary>> {
+    summarize(zip_file::read_files(dat_path, bin_path)?)
+}
+
+/// Compares the images in the DFU package at `zip_path` with the data the bootloader connected via
+/// `port` holds, without writing anything to the device.
+///
+/// Only the offsets and CRCs reported when selecting the command and data objects are compared,
+/// so this shows whether the device already holds (part of) an image, e.g. from an interrupted
+/// update. The device only keeps the data of the image transferred last, so at most one image of a
+/// package with several images is expected to match.
+pub fn verify(
+    port: Box<dyn SerialPort>,
+    zip_path: &str,
+    options: &UpdateOptions,
+) -> Result<Vec<VerifyReport>> {
+    let mut package =
+        zip_file::read_zip_file(zip_path, options.strict_dfu_version, &options.manifest_name)?;
+    let transport = SerialTransport::new(port).map_err(DfuError::Serial)?;
+    verify_package(Box::new(transport), &mut package, options)
+}
+
+/// Like [`verify`], but compares the loose init packet at `dat_path` and firmware image at
+/// `bin_path` instead of a DFU package. See [`run_with_files`].
+pub fn verify_files(
+    port: Box<dyn SerialPort>,
+    dat_path: &str,
+    bin_path: &str,
+    options: &UpdateOptions,
+) -> Result<Vec<VerifyReport>> {
+    let mut package = zip_file::read_files(dat_path, bin_path)?;
+    let transport = SerialTransport::new(port).map_err(DfuError::Serial)?;
+    verify_package(Box::new(transport), &mut package, options)
+}
+
+fn verify_package<R: Read + Seek>(
+    transport: Box<dyn Transport>,
+    package: &mut DfuPackage<R>,
+    options: &UpdateOptions,
+) -> Result<Vec<VerifyReport>> {
+    select_images(package, &options.only)?;
+    let mut conn = BootloaderConnection::connect_with_options(transport, options)?;
+    conn.set_read_retries(options.read_retries);
+    let crc = options.crc_algorithm;
+
+    let command = conn.select_object_command()?;
+    log::debug!("command object: {:?}", command);
+    let data = conn.select_object_data()?;
+    log::debug!("data object: {:?}", data);
+
+    let mut reports = Vec::new();
+    for index in 0..package.images.len() {
+        let image = &package.images[index];
+        let kind = image.kind;
+        let init_packet = compare_object(&image.dat[..], image.dat.len(), &command, crc)?;
+
+        let (bin_len, padded_len) = (image.bin_len, padded_len(image, options.pad)?);
+        let padding = io::repeat(options.pad_byte).take((padded_len - bin_len) as u64);
+        let bin = package.open_bin(index)?.chain(padding);
+        let firmware = compare_object(bin, padded_len, &data, crc)?;
+
+        log::debug!(
+            "{} image: init packet {:?}, firmware {:?}",
+            kind,
+            init_packet,
+            firmware
+        );
+        reports.push(VerifyReport {
+            kind,
+            init_packet,
+            firmware,
+        });
+    }
+    Ok(reports)
+}
+
+/// Compares the `len` bytes read from `data` with the offset and CRC (computed with `algorithm`) in
+/// `select_response`.
+fn compare_object(
+    data: impl Read,
+    len: usize,
+    select_response: &SelectResponse,
+    algorithm: CrcAlgorithm,
+) -> Result<ObjectMatch> {
+    let offset = select_response.offset;
+    let crc_matches = if offset as usize > len {
+        false
+    } else {
+        let crc = crc_reader(algorithm, data.take(u64::from(offset))).map_err(ZipError::Io)?;
+        crc == select_response.crc
+    };
+    Ok(ObjectMatch {
+        offset,
+        len: len as u32,
+        crc_matches,
+    })
+}
+
+fn summarize<R: Read + Seek>(mut package: DfuPackage<R>) -> Result<Vec<ImageSummary>> {
+    (0..package.images.len())
+        .map(|index| summarize_image(&mut package, index))
+        .collect()
+}
+
+/// Describes `package.images[index]`. This reads the whole firmware image to compute its CRC.
+fn summarize_image<R: Read + Seek>(
+    package: &mut DfuPackage<R>,
+    index: usize,
+) -> Result<ImageSummary> {
+    let bin_crc =
+        crc_reader(CrcAlgorithm::Crc32, package.open_bin(index)?).map_err(ZipError::Io)?;
+
+    let image = &package.images[index];
+    Ok(ImageSummary {
+        kind: image.kind,
+        dat_file: image.dat_file.clone(),
+        dat_len: image.dat.len(),
+        bin_file: image.bin_file.clone(),
+        bin_len: image.bin_len,
+        padded_len: padded_len(image, true)?,
+        bin_crc,
+        init_packet_data: image.init_packet_data.clone(),
+        init_packet: init_packet::describe(&image.dat)?,
+    })
+}
+
+/// Describes the images in `package` and logs a one-line summary of each, so that the operator
+/// can tell whether the intended build was loaded.
+fn log_package<R: Read + Seek>(package: &mut DfuPackage<R>) -> Result<Vec<ImageSummary>> {
+    let mut images = Vec::new();
+    for index in 0..package.images.len() {
+        let image = summarize_image(package, index)?;
+        log::info!(
+            "{} image: init packet {} bytes, firmware {} bytes ({} bytes padded), CRC32 0x{:08x}",
+            image.kind,
+            image.dat_len,
+            image.bin_len,
+            image.padded_len,
+            image.bin_crc
+        );
+        images.push(image);
+    }
+    Ok(images)
+}
+
+/// Removes all images from `package` whose kind is not listed in `only`, unless it is empty.
+///
+/// Fails if a kind listed in `only` is not present in the package.
+fn select_images<R>(package: &mut DfuPackage<R>, only: &[ImageKind]) -> Result<()> {
+    if only.is_empty() {
+        return Ok(());
+    }
+    if let Some(missing) = only
+        .iter()
+        .find(|&&kind| !package.images.iter().any(|image| image.kind == kind))
+    {
+        let present: Vec<_> = package
+            .images
+            .iter()
+            .map(|image| image.kind.to_string())
+            .collect();
+        return Err(DfuError::InvalidPackage(format!(
+            "package contains no {} image (it contains: {})",
+            missing,
+            present.join(", ")
+        )));
+    }
+    package.images.retain(|image| only.contains(&image.kind));
+    Ok(())
+}
+
+/// Like [`run`], but invokes `progress` after the init packet and every firmware data object
+/// have been transferred.
+pub fn run_with_progress(
+    port: Box<dyn SerialPort>,
+    zip_path: &str,
+    options: &UpdateOptions,
+    progress: impl FnMut(Progress),
+) -> Result<UpdateReport> {
+    let transport = SerialTransport::new(port).map_err(DfuError::Serial)?;
+    run_with_transport(Box::new(transport), zip_path, options, progress)
+}
+
+/// Like [`run_with_progress`], but talks to the bootloader via any [`Transport`] instead of a
+/// serial port.
+pub fn run_with_transport(
+    transport: Box<dyn Transport>,
+    zip_path: &str,
+    options: &UpdateOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<UpdateReport> {
+    let mut package =
+        zip_file::read_zip_file(zip_path, options.strict_dfu_version, &options.manifest_name)?;
+    update(transport, &mut package, options, &mut progress)
+}
+
+/// Like [`run_with_progress`], but flashes the loose init packet at `dat_path` and firmware image
+/// at `bin_path` instead of a DFU package.
+///
+/// This saves packaging the build output into a .zip file during development. The kind of the
+/// image (application, SoftDevice, ...) is taken from the init packet.
+pub fn run_with_files(
+    port: Box<dyn SerialPort>,
+    dat_path: &str,
+    bin_path: &str,
+    options: &UpdateOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<UpdateReport> {
+    let mut package = zip_file::read_files(dat_path, bin_path)?;
+    let transport = SerialTransport::new(port).map_err(DfuError::Serial)?;
+    update(Box::new(transport), &mut package, options, &mut progress)
+}
+
+/// Flashes the init packet `dat` and firmware image `bin` via the already established connection
+/// `conn`.
+///
+/// Unlike the other `run` functions, this skips the protocol version and MTU handshake, so that
+/// several images (or packages) can be flashed back-to-back over a single connection. The
+/// connection is configured according to `options` before anything is sent; options that only
+/// affect connecting, such as `handshake_retries`, are ignored.
+pub fn run_with_connection(
+    conn: &mut BootloaderConnection,
+    dat: &[u8],
+    bin: &[u8],
+    options: &UpdateOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<UpdateReport> {
+    let mut package = zip_file::read_bytes(dat, bin)?;
+    select_images(&mut package, &options.only)?;
+    let images = log_package(&mut package)?;
+    if let Some(timeout) = options.timeout {
+        conn.set_timeout(timeout)?;
+    }
+    block_on(update_connected(
+        &mut conn.inner,
+        &mut package,
+        images,
+        options,
+        &mut progress,
+    ))
+}
+
+/// Like [`run`], but reads the DFU package from `reader` instead of a file.
+///
+/// This allows flashing packages that only exist in memory, e.g. because they were downloaded.
+pub fn run_from_reader<R: Read + Seek>(
+    port: Box<dyn SerialPort>,
+    reader: R,
+    options: &UpdateOptions,
+) -> Result<UpdateReport> {
+    let mut package =
+        zip_file::read_zip(reader, options.strict_dfu_version, &options.manifest_name)?;
+    let transport = SerialTransport::new(port).map_err(DfuError::Serial)?;
+    update(Box::new(transport), &mut package, options, &mut |_| {})
+}
+
+/// Like [`run_from_reader`], but talks to the bootloader via any [`Transport`] and invokes
+/// `progress` like [`run_with_progress`].
+pub fn run_from_reader_with_transport<R: Read + Seek>(
+    transport: Box<dyn Transport>,
+    reader: R,
+    options: &UpdateOptions,
+    mut progress: impl FnMut(Progress),
+) -> Result<UpdateReport> {
+    let mut package =
+        zip_file::read_zip(reader, options.strict_dfu_version, &options.manifest_name)?;
+    update(transport, &mut package, options, &mut progress)
+}
+
+fn update<R: Read + Seek>(
+    transport: Box<dyn Transport>,
+    package: &mut DfuPackage<R>,
+    options: &UpdateOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<UpdateReport> {
+    block_on(update_via(transport, package, options, progress))
+}
+
+/// Connects to the bootloader via `transport` and flashes `package`. This is shared by the
+/// blocking and the async API.
+async fn update_via<L: Link, R: Read + Seek, P: FnMut(Progress) + ?Sized>(
+    mut transport: L,
+    package: &mut DfuPackage<R>,
+    options: &UpdateOptions,
+    progress: &mut P,
+) -> Result<UpdateReport> {
+    select_images(package, &options.only)?;
+    let images = log_package(package)?;
+
+    if let Some(timeout) = options.timeout {
+        transport.set_timeout(timeout).map_err(DfuError::Serial)?;
+    }
+    let mut conn = Connection::connect(
+        transport,
+        options.handshake_retries,
+        &options.accepted_protocol_versions,
+        options.force_protocol_version,
+    )
+    .await?;
+    update_connected(&mut conn, package, images, options, progress).await
+}
+
+/// Performs the update of [`update_via`] once connected, reporting the already summarized `images`.
+async fn update_connected<L: Link, R: Read + Seek, P: FnMut(Progress) + ?Sized>(
+    conn: &mut Connection<L>,
+    package: &mut DfuPackage<R>,
+    images: Vec<ImageSummary>,
+    options: &UpdateOptions,
+    progress: &mut P,
+) -> Result<UpdateReport> {
+    conn.resume = options.resume;
+    conn.chunk_retries = options.chunk_retries;
+    conn.cancel = options.cancel.clone();
+    conn.read_retries = options.read_retries;
+    conn.pad_byte = options.pad_byte;
+    conn.pad = options.pad;
+    conn.activation_timeout = options.activation_timeout;
+    conn.require_activation_response = options.require_activation_response;
+    conn.mtu_fallback_after = options.mtu_fallback_after;
+    if options.max_mtu != conn.max_mtu {
+        conn.set_max_mtu(options.max_mtu)?;
+    }
+    conn.inter_packet_delay = options.inter_packet_delay;
+    conn.opcode_timeouts = options.opcode_timeouts.clone();
+    conn.crc = options.crc_algorithm;
+
+    // Make sure the link works before sending anything substantial.
+    conn.ping().await?;
+
+    // Receipt notifications are disabled by default, since USB is a reliable transport.
+    conn.set_receipt_notification(options.prn).await?;
+
+    if options.reset_first {
+        log::info!("Aborting any pending transfer...");
+        conn.abort().await?;
+    }
+    let obj_select = conn.select_object_command().await;
+    log::debug!("select object response: {:?}", obj_select);
+
+    let version = conn.fetch_protocol_version().await?;
+    log::debug!("protocol version: {}", version);
+
+    let hw_version = conn.fetch_hardware_version().await?;
+    log::debug!("hardware version: {:?}", hw_version);
+    log::info!("Device: {}", hw_version);
+
+    if let Some(expected) = options.expected_part {
+        if hw_version.part != expected {
+            return Err(DfuError::WrongPart {
+                expected,
+                actual: hw_version.part,
+            });
+        }
+    }
+
+    let needs_sd_check = needs_sd_check(&package.images)?;
+    let installed = if needs_sd_check || options.skip_if_current {
+        let installed = conn.fetch_firmware_versions().await?;
+        log::debug!("installed firmware: {:?}", installed);
+        installed
+    } else {
+        Vec::new()
+    };
+
+    if needs_sd_check {
+        check_sd_req(&package.images, &installed, options.ignore_sd_req)?;
+    }
+
+    if options.skip_if_current && is_current(&package.images, &installed)? {
+        log::info!("Device already runs this firmware, skipping update");
+        return Ok(UpdateReport {
+            bytes_sent: 0,
+            chunks: 0,
+            duration: Duration::ZERO,
+            final_crc: 0,
+            skipped: true,
+            images,
+        });
+    }
+
+    let result = flash_images(conn, package, progress).await;
+    // On cancellation, the transfer has already been aborted. After a reset, there is nothing left
+    // to abort.
+    if result.is_err()
+        && !matches!(
+            result,
+            Err(DfuError::Cancelled | DfuError::ResetAfterActivation { .. })
+        )
+    {
+        // Don't leave a half-transferred object behind, so that the next attempt starts from a
+        // clean state.
+        if let Err(e) = conn.abort().await {
+            log::debug!("failed to abort transfer: {}", e);
+        }
+    }
+    let mut report = result?;
+    report.images = images;
+    Ok(report)
+}
+
+async fn flash_images<L: Link, R: Read + Seek, P: FnMut(Progress) + ?Sized>(
+    conn: &mut Connection<L>,
+    package: &mut DfuPackage<R>,
+    progress: &mut P,
+) -> Result<UpdateReport> {
+    let mut total = 0;
+    for image in &package.images {
+        total += padded_len(image, conn.pad)? as u64;
+    }
+    let mut sent_before = 0;
+    let mut report = UpdateReport {
+        bytes_sent: 0,
+        chunks: 0,
+        duration: Duration::ZERO,
+        final_crc: 0,
+        skipped: false,
+        images: Vec::new(),
+    };
+    for index in 0..package.images.len() {
+        let image = &package.images[index];
+        let kind = image.kind;
+        log::info!("Flashing {} image...", kind);
+        let (bin_len, image_total) = (image.bin_len, padded_len(image, conn.pad)? as u64);
+
+        conn.send_dat(&image.dat).await?;
+        progress(Progress {
+            sent: sent_before,
+            total,
+        });
+
+        let bin = package.open_bin(index)?;
+        let image_report = conn
+            .send_bin(bin, bin_len, &mut |p: Progress| {
+                progress(Progress {
+                    sent: sent_before + p.sent,
+                    total,
+                })
+            })
+            .await?;
+        sent_before += image_total;
+        report.bytes_sent += image_report.bytes_sent;
+        report.chunks += image_report.chunks;
+        report.duration += image_report.duration;
+        report.final_crc = image_report.final_crc;
+
+        // The bootloader resets to activate a SoftDevice or bootloader (and the application
+        // never comes first), so the rest of the package has to go over a new connection.
+        let remaining: Vec<_> = package.images[index + 1..]
+            .iter()
+            .map(|image| image.kind)
+            .collect();
+        if !remaining.is_empty() && (conn.reset_on_activation || kind != ImageKind::Application) {
+            log::info!("Device resets to activate the {} image", kind);
+            return Err(DfuError::ResetAfterActivation {
+                flashed: kind,
+                remaining,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+/// A connection to a DFU bootloader.
+///
+/// This allows building custom flashing flows out of the individual protocol requests. To simply
+/// flash a DFU package, use [`run`] instead.
+pub struct BootloaderConnection {
+    inner: Connection<Box<dyn Transport>>,
+}
+
+impl BootloaderConnection {
+    /// Connects to the bootloader on `port`.
+    ///
+    /// This checks that the bootloader speaks a supported protocol version and fetches its MTU.
+    pub fn new(port: Box<dyn SerialPort>) -> Result<Self> {
+        let transport = SerialTransport::new(port).map_err(DfuError::Serial)?;
+        Self::connect_with_options(Box::new(transport), &UpdateOptions::default())
+    }
+
+    /// Connects to the bootloader via `transport`, with the handshake retries and accepted
+    /// protocol versions of `options` (including
+    /// [`force_protocol_version`](UpdateOptions::force_protocol_version)).
+    pub fn connect_with_options(
+        transport: Box<dyn Transport>,
+        options: &UpdateOptions,
+    ) -> Result<Self> {
+        let inner = block_on(Connection::connect(
+            transport,
+            options.handshake_retries,
+            &options.accepted_protocol_versions,
+            options.force_protocol_version,
+        ))?;
+        Ok(Self { inner })
+    }
+
+    /// Whether [`send_dat`](Self::send_dat) and [`send_bin`](Self::send_bin) continue from the data
+    /// the device already holds, if it is valid. Disabled by default.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.inner.resume = resume;
+    }
+
+    /// Sets how often [`send_dat`](Self::send_dat) and [`send_bin`](Self::send_bin) re-send an
+    /// object after a CRC mismatch.
+    pub fn set_chunk_retries(&mut self, chunk_retries: u32) {
+        self.inner.chunk_retries = chunk_retries;
+    }
+
+    /// Sets how often reading a response is retried after it timed out. See
+    /// [`UpdateOptions::read_retries`].
+    pub fn set_read_retries(&mut self, read_retries: u32) {
+        self.inner.read_retries = read_retries;
+    }
+
+    /// Sets the byte that [`send_bin_from_reader`](Self::send_bin_from_reader) pads firmware
+    /// images with. See [`UpdateOptions::pad_byte`].
+    pub fn set_pad_byte(&mut self, pad_byte: u8) {
+        self.inner.pad_byte = pad_byte;
+    }
+
+    /// Sets whether [`send_bin_from_reader`](Self::send_bin_from_reader) pads firmware images to
+    /// a multiple of 4 bytes. See [`UpdateOptions::pad`].
+    pub fn set_padding(&mut self, pad: bool) {
+        self.inner.pad = pad;
+    }
+
+    /// Sets how long [`send_bin`](Self::send_bin) waits for the device to execute the last data
+    /// object. See [`UpdateOptions::activation_timeout`].
+    pub fn set_activation_timeout(&mut self, timeout: Option<Duration>) {
+        self.inner.activation_timeout = timeout;
+    }
+
+    /// Sets the pause between two successive write requests of an object. See
+    /// [`UpdateOptions::inter_packet_delay`].
+    pub fn set_inter_packet_delay(&mut self, delay: Duration) {
+        self.inner.inter_packet_delay = delay;
+    }
+
+    /// Sets whether [`send_bin`](Self::send_bin) fails if the device does not respond to executing
+    /// the last data object. See [`UpdateOptions::require_activation_response`].
+    pub fn set_require_activation_response(&mut self, require: bool) {
+        self.inner.require_activation_response = require;
+    }
+
+    /// Changes the read and write timeout of the connection. See [`UpdateOptions::timeout`].
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    /// Uses `algorithm` to check the CRCs reported by the device. See
+    /// [`UpdateOptions::crc_algorithm`].
+    pub fn set_crc_algorithm(&mut self, algorithm: CrcAlgorithm) {
+        self.inner.crc = algorithm;
+    }
+
+    /// Waits up to `timeout` for the responses to requests with `opcode`, or the regular timeout
+    /// if `None`. See [`UpdateOptions::opcode_timeouts`].
+    pub fn set_opcode_timeout(&mut self, opcode: OpCode, timeout: Option<Duration>) {
+        let timeouts = &mut self.inner.opcode_timeouts;
+        match timeout {
+            Some(timeout) => timeouts.insert(opcode, timeout),
+            None => timeouts.remove(&opcode),
+        };
+    }
+
+    /// Makes [`send_dat`](Self::send_dat) and [`send_bin`](Self::send_bin) halve the size of write
+    /// requests after `failures` objects had to be re-sent. See
+    /// [`UpdateOptions::mtu_fallback_after`].
+    pub fn set_mtu_fallback_after(&mut self, failures: u32) {
+        self.inner.mtu_fallback_after = failures;
+    }
+
+    /// Limits the MTU used to `max_mtu`, if the device reports a larger one. See
+    /// [`UpdateOptions::max_mtu`].
+    ///
+    /// Connecting already applies the default limit of 2048 bytes.
+    pub fn set_max_mtu(&mut self, max_mtu: u16) -> Result<()> {
+        self.inner.set_max_mtu(max_mtu)
+    }
+
+    /// Makes [`send_bin`](Self::send_bin) abort the transfer and return [`DfuError::Cancelled`]
+    /// once `cancel` is set. See [`UpdateOptions::cancel`].
+    pub fn set_cancel_flag(&mut self, cancel: Option<Arc<AtomicBool>>) {
+        self.inner.cancel = cancel;
+    }
+
+    /// Returns the version of the DFU protocol spoken by the bootloader.
+    pub fn fetch_protocol_version(&mut self) -> Result<ProtocolVersion> {
+        block_on(self.inner.fetch_protocol_version())
+    }
+
+    /// Pings the device and checks that it echoes the ping ID back.
+    pub fn ping(&mut self) -> Result<()> {
+        block_on(self.inner.ping())
+    }
+
+    /// Returns the part number and memory sizes of the device's chip.
+    pub fn fetch_hardware_version(&mut self) -> Result<HardwareVersion> {
+        block_on(self.inner.fetch_hardware_version())
+    }
+
+    /// Returns information about the firmware image with the number `image` (0 is the bootloader,
+    /// see [`fetch_firmware_versions`](Self::fetch_firmware_versions)).
+    pub fn fetch_firmware_version(&mut self, image: u8) -> Result<FirmwareVersionResponse> {
+        block_on(self.inner.fetch_firmware_version(image))
+    }
+
+    /// Returns information about all firmware images installed on the device.
+    ///
+    /// Returns an empty list if the bootloader does not support this request. Use
+    /// [`firmware_info`](Self::firmware_info) to tell the two cases apart.
+    pub fn fetch_firmware_versions(&mut self) -> Result<Vec<FirmwareVersionResponse>> {
+        block_on(self.inner.fetch_firmware_versions())
+    }
+
+    /// Returns the type, version, address and size of all firmware images installed on the
+    /// device, or `None` if the bootloader does not support querying them.
+    pub fn firmware_info(&mut self) -> Result<Option<Vec<FirmwareVersionResponse>>> {
+        block_on(self.inner.firmware_info())
+    }
+
+    /// Sends and executes the init packet (the `.dat` file of a DFU package).
+    ///
+    /// If the init packet is larger than the device's maximum command object size, it is split
+    /// into several command objects that are checked and executed one by one, like the data
+    /// objects in [`send_bin`](Self::send_bin).
+    pub fn send_dat(&mut self, data: &[u8]) -> Result<()> {
+        block_on(self.inner.send_dat(data))
+    }
+
+    /// Sends the firmware image described by the previously sent init packet.
+    ///
+    /// The image must already be padded to a multiple of 4 bytes. It is split into word-aligned
+    /// data objects that are checked and executed one by one; `progress` is invoked after each of
+    /// them.
+    pub fn send_bin(
+        &mut self,
+        image: &[u8],
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<UpdateReport> {
+        self.send_bin_from_reader(image, image.len(), progress)
+    }
+
+    /// Like [`send_bin`](Self::send_bin), but reads the `len` bytes of the firmware image from
+    /// `reader` while sending it.
+    ///
+    /// Only a single data object is held in memory at a time. The image is padded to a multiple of
+    /// 4 bytes as required by the bootloader (with 0xFF, unless changed with
+    /// [`set_pad_byte`](Self::set_pad_byte); padding can be disabled with
+    /// [`set_padding`](Self::set_padding)), so `reader` must yield the unpadded image.
+    pub fn send_bin_from_reader(
+        &mut self,
+        reader: impl Read,
+        len: usize,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<UpdateReport> {
+        block_on(self.inner.send_bin(reader, len, progress))
+    }
+
+    /// Sends a
+    /// Request Type: `Select`
+    /// Parameters:   `Object type = Command`
+    pub fn select_object_command(&mut self) -> Result<SelectResponse> {
+        block_on(self.inner.select_object_command())
+    }
+
+    /// Sends a
+    /// Request Type: `Select`
+    /// Parameters:   `Object type = Data`
+    pub fn select_object_data(&mut self) -> Result<SelectResponse> {
+        block_on(self.inner.select_object_data())
+    }
+
+    /// Sends a
+    /// Request Type: `Create`
+    /// Parameters:   `Object type = Command`
+    ///               `size`
+    pub fn create_command_object(&mut self, size: u32) -> Result<()> {
+        block_on(self.inner.create_command_object(size))
+    }
+
+    /// Sends a
+    /// Request Type: `Create`
+    /// Parameters:   `Object type = Data`
+    ///               `size`
+    pub fn create_data_object(&mut self, size: u32) -> Result<()> {
+        block_on(self.inner.create_data_object(size))
+    }
+
+    /// Makes the device confirm every `every_n_packets`th write request (0 disables this).
+    pub fn set_receipt_notification(&mut self, every_n_packets: u16) -> Result<()> {
+        block_on(self.inner.set_receipt_notification(every_n_packets))
+    }
+
+    /// Returns the maximum size of an encoded request the device accepts.
+    pub fn fetch_mtu(&mut self) -> Result<u16> {
+        block_on(self.inner.fetch_mtu())
+    }
+
+    /// Writes `data` to the current object, split into as many requests as the MTU requires.
+    ///
+    /// `initial_offset` and `initial_crc` are the size and CRC of the data the device holds for the
+    /// current object type before `data`. If packet receipt notifications are enabled, the offset
+    /// and CRC in every receipt are checked against them, continued over the data streamed so far.
+    pub fn stream_object_data(
+        &mut self,
+        data: &[u8],
+        initial_offset: usize,
+        initial_crc: u32,
+    ) -> Result<()> {
+        block_on(
+            self.inner
+                .stream_object_data(data, initial_offset, initial_crc),
+        )
+    }
+
+    /// Aborts the current transfer, discarding any object that has not been executed yet.
+    ///
+    /// The bootloader does not send a response to this request (it may reset instead).
+    pub fn abort(&mut self) -> Result<()> {
+        block_on(self.inner.abort())
+    }
+
+    /// Returns the offset and CRC of the data received for the current object type.
+    pub fn get_crc(&mut self) -> Result<CrcResponse> {
+        block_on(self.inner.get_crc())
+    }
+
+    // tell the target to execute whatever request setup we sent them before
+    pub fn execute(&mut self) -> Result<()> {
+        block_on(self.inner.execute())
+    }
+}
+
+/// Describes the progress of a transfer, e.g. `sent 40960/262144 bytes (15%), 8.2 KiB/s, ETA 27s`.
+///
+/// `offset` is the position in the image of size `total`, while the speed is calculated from the
+/// `bytes_sent` during `elapsed` (which differ when resuming a transfer).
+fn format_throughput(offset: usize, total: usize, bytes_sent: u64, elapsed: Duration) -> String {
+    let percent = (offset * 100).checked_div(total).unwrap_or(100);
+    let bytes_per_sec = bytes_sent as f64 / elapsed.as_secs_f64();
+    let mut msg = format!(
+        "sent {}/{} bytes ({}%), {:.1} KiB/s",
+        offset,
+        total,
+        percent,
+        bytes_per_sec / 1024.0
+    );
+    if bytes_per_sec > 0.0 {
+        let eta = (total - offset) as f64 / bytes_per_sec;
+        msg += &format!(", ETA {:.0}s", eta);
+    }
+    msg
+}
+
+/// Splits a firmware image into data objects while reading it.
+struct ObjectReader<R> {
+    reader: R,
+    /// Number of image bytes not read from `reader` yet.
+    remaining: usize,
+    /// Size of the image after padding.
+    padded_len: usize,
+    /// Number of bytes (including padding) not returned from `next_object` yet.
+    remaining_padded: usize,
+    /// Size of the data objects to return (except for the last one, which may be smaller).
+    object_size: usize,
+    pad_byte: u8,
+}
+
+impl<R: Read> ObjectReader<R> {
+    /// Creates a reader for an image of `len` bytes, returning objects of at most `max_size`
+    /// bytes. The image is padded with `pad_byte`, unless it is `None`.
+    ///
+    /// Some bootloaders expect every data object to be word-aligned, so the object size is
+    /// rounded down to a multiple of 4 bytes.
+    fn new(reader: R, len: usize, max_size: usize, pad_byte: Option<u8>) -> Self {
+        // The firmware image must be padded to be a multiple of 4 Bytes. To our knowledge,
+        // this is undocumented.
+        let padded_len = match pad_byte {
+            Some(_) => len.next_multiple_of(4),
+            None => len,
+        };
+        Self {
+            reader,
+            remaining: len,
+            padded_len,
+            remaining_padded: padded_len,
+            object_size: max_size - max_size % 4,
+            pad_byte: pad_byte.unwrap_or(DEFAULT_PAD_BYTE),
+        }
+    }
+
+    /// Returns the size of the padded image.
+    fn len(&self) -> usize {
+        self.padded_len
+    }
+
+    /// Reads the next data object, or returns `None` after the last one.
+    fn next_object(&mut self) -> Result<Option<Vec<u8>>> {
+        let size = usize::min(self.object_size, self.remaining_padded);
+        if size == 0 {
+            return Ok(None);
+        }
+
+        let mut object = vec![self.pad_byte; size];
+        let data_len = usize::min(size, self.remaining);
+        self.reader
+            .read_exact(&mut object[..data_len])
+            .map_err(ZipError::Io)?;
+        self.remaining -= data_len;
+        self.remaining_padded -= size;
+        Ok(Some(object))
+    }
+}
+
+/// Returns whether the device holds firmware data (described by the `select_response` for the data
+/// object type) that a transfer of an image of `len` bytes can't continue from.
+///
+/// Unless `resume` is set, any data is stale. Otherwise, `BootloaderConnection::resume_data`
+/// takes care of data that doesn't match the image, as long as it is not longer than the image.
+fn is_stale(select_response: &SelectResponse, len: usize, resume: bool) -> bool {
+    let offset = select_response.offset as usize;
+    offset != 0 && (!resume || offset > len)
+}
+
+/// Checks that the bootloader speaks protocol `version`, which has to be [`PROTOCOL_VERSION`]
+/// unless it is explicitly `accepted` or `force` is set.
+fn check_protocol_version(version: u8, accepted: &[u8], force: bool) -> Result<()> {
+    if version == PROTOCOL_VERSION {
+        return Ok(());
+    }
+    if force || accepted.contains(&version) {
+        log::warn!(
+            "device reports unsupported protocol version {} (expected {}), continuing anyway; \
+            if the protocol differs, the update may fail or leave the device without working \
+            firmware",
+            version,
+            PROTOCOL_VERSION
+        );
+        return Ok(());
+    }
+    Err(DfuError::UnsupportedProtocolVersion {
+        got: version,
+        supported: PROTOCOL_VERSION,
+    })
+}
+
+/// Returns whether `err` is a timeout waiting for the device.
+fn is_timeout(err: &DfuError) -> bool {
+    matches!(err.root(), DfuError::Serial(e) if e.kind() == io::ErrorKind::TimedOut)
+}
+
+/// Returns whether `err` is the bootloader's response to a request it does not implement.
+fn is_unsupported(err: &DfuError) -> bool {
+    matches!(
+        err,
+        DfuError::Protocol {
+            result_code: ResultCode::OpCodeNotSupported,
+            ..
+        }
+    )
+}
+
+/// Returns whether the device already runs all `images`, going by the firmware versions
+/// `installed` on it.
+fn is_current(images: &[FirmwareImage], installed: &[FirmwareVersionResponse]) -> Result<bool> {
+    for image in images {
+        let fw_type = match image.kind {
+            ImageKind::Application => FirmwareType::Application,
+            ImageKind::Softdevice => FirmwareType::Softdevice,
+            // The init packet of a combined image declares the version of the bootloader.
+            ImageKind::Bootloader | ImageKind::SoftdeviceBootloader => FirmwareType::Bootloader,
+        };
+        let version = init_packet::firmware_version(&image.dat)?;
+        if !installed
+            .iter()
+            .any(|fw| fw.fw_type == fw_type && fw.version == version)
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// `sd_req` entry of images that don't need a SoftDevice.
+const SD_REQ_NONE: u32 = 0x00;
+
+/// `sd_req` entry of images that work with any SoftDevice.
+const SD_REQ_ANY: u32 = 0xFFFE;
+
+/// Returns whether any of `images` has SoftDevice requirements that [`check_sd_req`] can check.
+///
+/// Packages that bring their own SoftDevice are not checked, since it replaces the device's.
+fn needs_sd_check(images: &[FirmwareImage]) -> Result<bool> {
+    if images.iter().any(|image| {
+        matches!(
+            image.kind,
+            ImageKind::Softdevice | ImageKind::SoftdeviceBootloader
+        )
+    }) {
+        return Ok(false);
+    }
+    for image in images {
+        let sd_req = init_packet::describe(&image.dat)?.sd_req;
+        if !sd_req.is_empty() && !sd_req.contains(&SD_REQ_ANY) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Checks whether the device, going by the firmware `installed` on it, has a SoftDevice if and
+/// only if `images` require one.
+///
+/// A mismatch is an error, unless `ignore` is set, in which case it is only logged.
+fn check_sd_req(
+    images: &[FirmwareImage],
+    installed: &[FirmwareVersionResponse],
+    ignore: bool,
+) -> Result<()> {
+    if installed.is_empty() {
+        log::debug!("bootloader does not report its firmware, skipping sd_req check");
+        return Ok(());
+    }
+    let softdevice = installed
+        .iter()
+        .find(|fw| fw.fw_type == FirmwareType::Softdevice && fw.len != 0);
+    for image in images {
+        let sd_req = init_packet::describe(&image.dat)?.sd_req;
+        if sd_req.is_empty() || sd_req.contains(&SD_REQ_ANY) {
+            continue;
+        }
+        let mismatch = match softdevice {
+            None if !sd_req.contains(&SD_REQ_NONE) => {
+                let ids = sd_req
+                    .iter()
+                    .map(|id| format!("0x{:04x}", id))
+                    .collect::<Vec<_>>();
+                format!(
+                    "{} image requires SoftDevice {}, but the device has none",
+                    image.kind,
+                    ids.join("/")
+                )
+            }
+            Some(softdevice) if sd_req.iter().all(|&id| id == SD_REQ_NONE) => format!(
+                "{} image requires no SoftDevice, but the device has one (version {})",
+                image.kind, softdevice.version
+            ),
+            _ => continue,
+        };
+        if !ignore {
+            return Err(DfuError::SoftDeviceMismatch(mismatch));
+        }
+        log::warn!("{}", mismatch);
+    }
+    Ok(())
+}
+
+/// Returns the delay before the `attempt`th retry of a timed out read.
+fn read_retry_delay(attempt: u32) -> Duration {
+    READ_RETRY_BASE_DELAY * 2u32.pow(attempt.saturating_sub(1).min(10))
+}
+
+/// Returns whether the cancellation flag `cancel` is set.
+fn is_cancelled(cancel: &Option<Arc<AtomicBool>>) -> bool {
+    cancel
+        .as_ref()
+        .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+}
+
+/// Checks that `received_crc` is the CRC of `data` computed with `algorithm`, continued from
+/// `initial`.
+///
+/// Returns the CRC on success, so that it can be used as `initial` for the data that follows.
+fn check_crc(algorithm: CrcAlgorithm, data: &[u8], received_crc: u32, initial: u32) -> Result<u32> {
+    let expected_crc = algorithm.update(initial, data);
+
+    if expected_crc == received_crc {
+        log::debug!("crc passed.");
+        Ok(expected_crc)
+    } else {
+        let err = DfuError::Crc {
+            expected: expected_crc,
+            received: received_crc,
+            object: None,
+            offset: None,
+        };
+        log::debug!("{}", err);
+        Err(err)
+    }
+}
+
+/// Checks that the device's CRC over the whole firmware image, `received_crc`, matches the CRC
+/// of the (padded) image that was sent.
+fn check_image_crc(received_crc: u32, expected_crc: u32) -> Result<()> {
+    if received_crc != expected_crc {
+        return Err(DfuError::Crc {
+            expected: expected_crc,
+            received: received_crc,
+            object: None,
+            offset: None,
+        });
+    }
+    log::info!("Firmware image CRC: 0x{:08x}", received_crc);
+    Ok(())
+}
+
+/// Checks the offset and CRC32 reported in a packet receipt notification against the
+/// `expected_offset` and `expected_crc` of the data streamed so far.
+///
+/// The offset is checked first: if write requests were lost, the CRC can't match either, but the
+/// offset says what went wrong.
+fn check_receipt(receipt: &CrcResponse, expected_offset: usize, expected_crc: u32) -> Result<()> {
+    let err = if receipt.offset as usize != expected_offset {
+        DfuError::OffsetMismatch {
+            expected: expected_offset as u32,
+            received: receipt.offset,
+        }
+    } else if receipt.crc != expected_crc {
+        DfuError::Crc {
+            expected: expected_crc,
+            received: receipt.crc,
+            object: None,
+            offset: None,
+        }
+    } else {
+        return Ok(());
+    };
+    log::debug!("receipt notification: {}", err);
+    Err(err)
+}
+
+/// Returns the write request size to fall back to from `current`, or `None` if it can't be reduced
+/// any further. See [`UpdateOptions::mtu_fallback_after`].
+fn reduced_chunk_size(current: usize) -> Option<usize> {
+    let reduced = (current / 2).max(MIN_FALLBACK_CHUNK_SIZE);
+    if reduced < current {
+        Some(reduced)
+    } else {
+        None
+    }
+}
+
+/// Adds the `index` and `offset` of the object it occurred in to a CRC mismatch. Other errors are
+/// returned unchanged.
+fn locate_crc_error(err: DfuError, index: usize, offset: usize) -> DfuError {
+    match err {
+        DfuError::Crc {
+            expected, received, ..
+        } => DfuError::Crc {
+            expected,
+            received,
+            object: Some(index as u32),
+            offset: Some(offset as u32),
+        },
+        err => err,
+    }
+}
+
+/// Computes the CRC of everything `reader` yields, using `algorithm`.
+fn crc_reader(algorithm: CrcAlgorithm, mut reader: impl Read) -> io::Result<u32> {
+    let mut crc = 0;
+    let mut buf = [0; 4096];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(crc);
+        }
+        crc = algorithm.update(crc, &buf[..n]);
+    }
+}
+
+/// Returns the size of `image`s firmware after padding it to a multiple of 4 bytes, if `pad` is
+/// set.
+///
+/// Fails if the padded image is too large to be transferred, since the protocol uses 32-bit sizes.
+fn padded_len(image: &FirmwareImage, pad: bool) -> Result<usize> {
+    let len = if pad {
+        image.bin_len.next_multiple_of(4)
+    } else {
+        image.bin_len
+    };
+    if u32::try_from(len).is_err() {
+        return Err(DfuError::InvalidPackage(format!(
+            "{} image is too large ({} bytes)",
+            image.kind, image.bin_len
+        )));
+    }
+    Ok(len)
+}
+
+#[derive(Debug, Deserialize)]
+struct Image {
+    dat_file: String,
+    bin_file: String,
+    init_packet_data: Option<InitPacketData>,
+}
+
+/// Firmware metadata that some `nrfutil` versions add to the manifest of a DFU package.
+///
+/// This duplicates information from the init packet, and is purely informational: only the init
+/// packet is sent to the device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[non_exhaustive]
+pub struct InitPacketData {
+    pub application_version: Option<u32>,
+    pub device_revision: Option<u32>,
+    pub device_type: Option<u32>,
+    pub firmware_crc16: Option<u32>,
+    pub firmware_hash: Option<String>,
+    /// Accepted SoftDevice firmware IDs.
+    #[serde(default)]
+    pub softdevice_req: Vec<u32>,
+}
+
+impl fmt::Display for InitPacketData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(version) = self.application_version {
+            fields.push(format!("application version {}", version));
+        }
+        if let Some(device_type) = self.device_type {
+            fields.push(format!("device type 0x{:04x}", device_type));
+        }
+        if let Some(revision) = self.device_revision {
+            fields.push(format!("device revision 0x{:04x}", revision));
+        }
+        if !self.softdevice_req.is_empty() {
+            let ids = self
+                .softdevice_req
+                .iter()
+                .map(|id| format!("0x{:04x}", id))
+                .collect::<Vec<_>>();
+            fields.push(format!("SoftDevice {}", ids.join("/")));
+        }
+        if let Some(crc) = self.firmware_crc16 {
+            fields.push(format!("CRC16 0x{:04x}", crc));
+        }
+        if let Some(hash) = &self.firmware_hash {
+            fields.push(format!("hash {}", hash));
+        }
+        f.write_str(&fields.join(", "))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    dfu_version: Option<f64>,
+    softdevice_bootloader: Option<Image>,
+    softdevice: Option<Image>,
+    bootloader: Option<Image>,
+    application: Option<Image>,
+}
+
+impl Manifest {
+    /// Returns the images listed in the manifest, in the order they have to be flashed in.
+    fn images(&self) -> impl Iterator<Item = (zip_file::ImageKind, &Image)> {
+        use zip_file::ImageKind;
+
+        vec![
+            (ImageKind::SoftdeviceBootloader, &self.softdevice_bootloader),
+            (ImageKind::Softdevice, &self.softdevice),
+            (ImageKind::Bootloader, &self.bootloader),
+            (ImageKind::Application, &self.application),
+        ]
+        .into_iter()
+        .filter_map(|(kind, image)| image.as_ref().map(|image| (kind, image)))
+    }
+
+    /// Describes the images listed in the manifest, including their `init_packet_data`.
+    fn describe(&self) -> String {
+        self.images()
+            .map(|(kind, image)| {
+                let mut line = format!("{}: {}, {}", kind, image.dat_file, image.bin_file);
+                if let Some(data) = &image.init_packet_data {
+                    line += &format!(" ({})", data);
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OuterManifest {
+    manifest: Manifest,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::convert::TryInto;
+    use std::io;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    /// Transport that replays canned responses and records all requests.
+    struct MockTransport {
+        responses: VecDeque<io::Result<Vec<u8>>>,
+        /// Shared with the test, since the transport itself is moved into the connection.
+        requests: Rc<RefCell<Vec<Vec<u8>>>>,
+        timeout: Duration,
+        /// The timeout in effect for every response received, shared like `requests`.
+        timeouts: Rc<RefCell<Vec<Duration>>>,
+        /// When each request was sent, shared like `requests`.
+        sent_at: Rc<RefCell<Vec<Instant>>>,
+        /// Whether to limit frames to what fits into the MTU after SLIP encoding, like the serial
+        /// transport does, instead of using the whole MTU.
+        slip_framing: bool,
+    }
+
+    impl MockTransport {
+        fn new(responses: Vec<io::Result<Vec<u8>>>) -> Self {
+            Self {
+                responses: responses.into(),
+                requests: Rc::default(),
+                timeout: Duration::from_secs(1),
+                timeouts: Rc::default(),
+                sent_at: Rc::default(),
+                slip_framing: false,
+            }
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.requests.borrow_mut().push(frame.to_vec());
+            self.sent_at.borrow_mut().push(Instant::now());
+            Ok(())
+        }
+
+        fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+            self.timeouts.borrow_mut().push(self.timeout);
+            let response = self
+                .responses
+                .pop_front()
+                .expect("no more responses scripted")?;
+            buf.extend_from_slice(&response);
+            Ok(())
+        }
+
+        fn max_frame_size(&self, mtu: u16) -> usize {
+            if self.slip_framing {
+                slip::max_frame_len(usize::from(mtu))
+            } else {
+                usize::from(mtu)
+            }
+        }
+
+        fn timeout(&self) -> Option<Duration> {
+            Some(self.timeout)
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+    }
+
+    fn timeout() -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::TimedOut.into())
+    }
+
+    /// Returns a successful response to a request with `opcode`.
+    fn ok(opcode: OpCode, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut response = vec![
+            OpCode::Response as u8,
+            opcode as u8,
+            ResultCode::Success as u8,
+        ];
+        response.extend_from_slice(payload);
+        Ok(response)
+    }
+
+    fn select_ok(max_size: u32, offset: u32, crc: u32) -> io::Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        for value in &[max_size, offset, crc] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        ok(OpCode::Select, &payload)
+    }
+
+    fn crc_ok(offset: u32, crc: u32) -> io::Result<Vec<u8>> {
+        let mut payload = offset.to_le_bytes().to_vec();
+        payload.extend_from_slice(&crc.to_le_bytes());
+        ok(OpCode::Crc, &payload)
+    }
+
+    /// Creates a connection that has already completed the handshake, returning it along with the
+    /// requests it sends.
+    fn connect(
+        mtu: u16,
+        responses: Vec<io::Result<Vec<u8>>>,
+    ) -> (BootloaderConnection, Rc<RefCell<Vec<Vec<u8>>>>) {
+        let transport = MockTransport::new(responses);
+        let requests = transport.requests.clone();
+        let mut inner = Connection::new(Box::new(transport) as Box<dyn Transport>);
+        inner.mtu = mtu;
+        let conn = BootloaderConnection { inner };
+        (conn, requests)
+    }
+
+    #[test]
+    fn test_send_dat() {
+        let data = [1, 2, 3, 4, 5];
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(512, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(5, crc32fast::hash(&data)),
+                ok(OpCode::Execute, &[]),
+            ],
+        );
+        conn.send_dat(&data).unwrap();
+
+        assert_eq!(
+            *requests.borrow(),
+            vec![
+                vec![0x06, 0x01],
+                vec![0x01, 0x01, 5, 0, 0, 0],
+                vec![0x08, 1, 2, 3, 4, 5],
+                vec![0x03],
+                vec![0x04],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_dat_crc_mismatch() {
+        let data = [1, 2, 3, 4, 5];
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(512, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(5, !crc32fast::hash(&data)),
+            ],
+        );
+        let err = conn.send_dat(&data).unwrap_err();
+        assert!(matches!(err, DfuError::Crc { .. }), "{:?}", err);
+        // The corrupted object must not be executed.
+        assert_ne!(requests.borrow().last().unwrap(), &vec![0x04]);
+    }
+
+    #[test]
+    fn test_send_dat_retry() {
+        let data = [1, 2, 3, 4, 5];
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(512, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(5, !crc32fast::hash(&data)),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(5, crc32fast::hash(&data)),
+                ok(OpCode::Execute, &[]),
+            ],
+        );
+        conn.set_chunk_retries(1);
+        conn.send_dat(&data).unwrap();
+
+        assert_eq!(requests.borrow()[4], vec![0x01, 0x01, 5, 0, 0, 0]);
+        assert_eq!(requests.borrow().last().unwrap(), &vec![0x04]);
+    }
+
+    #[test]
+    fn test_send_dat_multiple_objects() {
+        let data = [1, 2, 3, 4, 5];
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(2, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(2, crc32fast::hash(&data[..2])),
+                ok(OpCode::Execute, &[]),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(4, crc32fast::hash(&data[..4])),
+                ok(OpCode::Execute, &[]),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(5, crc32fast::hash(&data)),
+                ok(OpCode::Execute, &[]),
+            ],
+        );
+        conn.send_dat(&data).unwrap();
+
+        assert_eq!(
+            requests.borrow()[1..],
+            [
+                vec![0x01, 0x01, 2, 0, 0, 0],
+                vec![0x08, 1, 2],
+                vec![0x03],
+                vec![0x04],
+                vec![0x01, 0x01, 2, 0, 0, 0],
+                vec![0x08, 3, 4],
+                vec![0x03],
+                vec![0x04],
+                vec![0x01, 0x01, 1, 0, 0, 0],
+                vec![0x08, 5],
+                vec![0x03],
+                vec![0x04],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_bin_from_reader_pads_last_object() {
+        let image = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let padded = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0xff, 0xff];
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(8, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&padded[..8])),
+                ok(OpCode::Execute, &[]),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(12, crc32fast::hash(&padded)),
+                ok(OpCode::Execute, &[]),
+                crc_ok(12, crc32fast::hash(&padded)),
+            ],
+        );
+        let mut progress = Vec::new();
+        let report = conn
+            .send_bin_from_reader(&image[..], image.len(), &mut |p| progress.push(p.sent))
+            .unwrap();
+
+        assert_eq!(report.bytes_sent, 12);
+        assert_eq!(report.chunks, 2);
+        assert_eq!(report.final_crc, crc32fast::hash(&padded));
+        assert_eq!(progress, vec![8, 12]);
+        assert_eq!(
+            requests.borrow()[5..8],
+            [
+                vec![0x01, 0x02, 4, 0, 0, 0],
+                vec![0x08, 9, 10, 0xff, 0xff],
+                vec![0x03],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_bin_discards_stale_object() {
+        let image = [1, 2, 3, 4, 5, 6, 7, 8];
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                // A previous run left 4 bytes of some other image behind.
+                select_ok(8, 4, 0xdeadbeef),
+                select_ok(8, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&image)),
+                ok(OpCode::Execute, &[]),
+                crc_ok(8, crc32fast::hash(&image)),
+            ],
+        );
+        let report = conn
+            .send_bin_from_reader(&image[..], image.len(), &mut |_| {})
+            .unwrap();
+
+        assert_eq!(report.chunks, 1);
+        assert_eq!(
+            requests.borrow()[..4],
+            [
+                vec![0x06, 0x02],
+                vec![0x0C],
+                vec![0x06, 0x02],
+                vec![0x01, 0x02, 8, 0, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_bin_activation_timeout() {
+        let image: Vec<u8> = (0..16).collect();
+        let transport = MockTransport::new(vec![
+            select_ok(8, 0, 0),
+            ok(OpCode::CreateObject, &[]),
+            crc_ok(8, crc32fast::hash(&image[..8])),
+            ok(OpCode::Execute, &[]),
+            ok(OpCode::CreateObject, &[]),
+            crc_ok(16, crc32fast::hash(&image)),
+            timeout(),
+            timeout(),
+            ok(OpCode::Execute, &[]),
+            crc_ok(16, crc32fast::hash(&image)),
+        ]);
+        let timeouts = transport.timeouts.clone();
+        let (mut conn, _) = connect(64, vec![]);
+        conn.inner.transport = Box::new(transport);
+        conn.set_activation_timeout(Some(Duration::from_secs(30)));
+        conn.send_bin(&image, &mut |_| {}).unwrap();
+
+        // Only executing the last object waits longer, in slices between which a heartbeat is
+        // logged.
+        let timeouts = timeouts.borrow();
+        let regular = Duration::from_secs(1);
+        assert!(timeouts[..6].iter().all(|&t| t == regular));
+        assert_eq!(timeouts[6..9], [ACTIVATION_HEARTBEAT_INTERVAL; 3]);
+        assert_eq!(timeouts[9], regular);
+    }
+
+    #[test]
+    fn test_send_bin_activation_timeout_exhausted() {
+        let image: Vec<u8> = (0..8).collect();
+        let mut responses = vec![
+            select_ok(8, 0, 0),
+            ok(OpCode::CreateObject, &[]),
+            crc_ok(8, crc32fast::hash(&image)),
+        ];
+        responses.extend((0..3).map(|_| timeout()));
+        let transport = MockTransport::new(responses);
+        let timeouts = transport.timeouts.clone();
+        let (mut conn, _) = connect(64, vec![]);
+        conn.inner.transport = Box::new(transport);
+        conn.set_activation_timeout(Some(Duration::from_secs(12)));
+        conn.set_require_activation_response(true);
+        let err = conn.send_bin(&image, &mut |_| {}).unwrap_err();
+        assert!(is_timeout(&err), "{:?}", err);
+
+        // The slices add up to the activation timeout.
+        assert_eq!(
+            timeouts.borrow()[3..],
+            [
+                Duration::from_secs(5),
+                Duration::from_secs(5),
+                Duration::from_secs(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_bin_activation_without_response() {
+        let image: Vec<u8> = (0..16).collect();
+        let responses = || {
+            vec![
+                select_ok(8, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&image[..8])),
+                ok(OpCode::Execute, &[]),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(16, crc32fast::hash(&image)),
+                timeout(),
+            ]
+        };
+
+        // The device reset to activate the image, so the final CRC check is skipped.
+        let (mut conn, requests) = connect(64, responses());
+        let report = conn.send_bin(&image, &mut |_| {}).unwrap();
+        assert_eq!(report.final_crc, crc32fast::hash(&image));
+        assert_eq!(requests.borrow().last().unwrap()[0], OpCode::Execute as u8);
+
+        let (mut conn, _) = connect(64, responses());
+        conn.set_require_activation_response(true);
+        let err = conn.send_bin(&image, &mut |_| {}).unwrap_err();
+        assert!(is_timeout(&err), "{:?}", err);
+
+        // Only the last object may go unanswered.
+        let (mut conn, _) = connect(
+            64,
+            vec![
+                select_ok(8, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&image[..8])),
+                timeout(),
+            ],
+        );
+        let err = conn.send_bin(&image, &mut |_| {}).unwrap_err();
+        assert!(is_timeout(&err), "{:?}", err);
+
+        // Protocol errors are never mistaken for a reset.
+        let mut responses = responses();
+        *responses.last_mut().unwrap() = Ok(vec![
+            OpCode::Response as u8,
+            OpCode::Execute as u8,
+            ResultCode::OperationFailed as u8,
+        ]);
+        let (mut conn, _) = connect(64, responses);
+        let err = conn.send_bin(&image, &mut |_| {}).unwrap_err();
+        assert!(matches!(err.root(), DfuError::Protocol { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_set_timeout() {
+        let transport = MockTransport::new(vec![ok(OpCode::MtuGet, &[64, 0])]);
+        let timeouts = transport.timeouts.clone();
+        let (mut conn, _) = connect(64, vec![]);
+        conn.inner.transport = Box::new(transport);
+        conn.set_timeout(Duration::from_millis(250)).unwrap();
+        conn.fetch_mtu().unwrap();
+        assert_eq!(*timeouts.borrow(), vec![Duration::from_millis(250)]);
+    }
+
+    #[test]
+    fn test_opcode_timeouts() {
+        let image: Vec<u8> = (0..8).collect();
+        let transport = MockTransport::new(vec![
+            select_ok(8, 0, 0),
+            ok(OpCode::CreateObject, &[]),
+            crc_ok(8, crc32fast::hash(&image)),
+            ok(OpCode::Execute, &[]),
+            crc_ok(8, crc32fast::hash(&image)),
+        ]);
+        let timeouts = transport.timeouts.clone();
+        let (mut conn, _) = connect(64, vec![]);
+        conn.inner.transport = Box::new(transport);
+        conn.set_opcode_timeout(OpCode::Crc, Some(Duration::from_millis(100)));
+        conn.set_opcode_timeout(OpCode::Execute, Some(Duration::from_secs(4)));
+        conn.set_activation_timeout(Some(Duration::from_secs(3)));
+        conn.send_bin(&image, &mut |_| {}).unwrap();
+
+        // The activation timeout wins over the one for `Execute`.
+        let regular = Duration::from_secs(1);
+        let fast = Duration::from_millis(100);
+        assert_eq!(
+            *timeouts.borrow(),
+            vec![regular, regular, fast, Duration::from_secs(3), fast]
+        );
+
+        conn.set_opcode_timeout(OpCode::Crc, None);
+        assert!(!conn.inner.opcode_timeouts.contains_key(&OpCode::Crc));
+    }
+
+    #[test]
+    fn test_inter_packet_delay() {
+        let delay = Duration::from_millis(50);
+        let transport = MockTransport::new(vec![]);
+        let sent_at = transport.sent_at.clone();
+        let (mut conn, _) = connect(10, vec![]);
+        conn.inner.transport = Box::new(transport);
+        conn.set_inter_packet_delay(delay);
+
+        let start = Instant::now();
+        conn.stream_object_data(&[0; 27], 0, 0).unwrap();
+
+        // Three write requests of 9 bytes each, with a pause between each of them but not before
+        // the first one.
+        let sent_at = sent_at.borrow();
+        assert_eq!(sent_at.len(), 3);
+        assert!(sent_at[0] - start < delay);
+        assert!(sent_at.windows(2).all(|w| w[1] - w[0] >= delay));
+    }
+
+    #[test]
+    fn test_send_bin_custom_pad_byte() {
+        let image = [1, 2, 3, 4, 5, 6];
+        let padded = [1, 2, 3, 4, 5, 6, 0, 0];
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(64, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&padded)),
+                ok(OpCode::Execute, &[]),
+                crc_ok(8, crc32fast::hash(&padded)),
+            ],
+        );
+        conn.set_pad_byte(0x00);
+        conn.send_bin_from_reader(&image[..], image.len(), &mut |_| {})
+            .unwrap();
+
+        assert_eq!(requests.borrow()[2], [&[0x08][..], &padded].concat());
+    }
+
+    #[test]
+    fn test_send_bin_no_padding() {
+        let image = [1, 2, 3, 4, 5, 6];
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(64, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(6, crc32fast::hash(&image)),
+                ok(OpCode::Execute, &[]),
+                crc_ok(6, crc32fast::hash(&image)),
+            ],
+        );
+        conn.set_padding(false);
+        let report = conn
+            .send_bin_from_reader(&image[..], image.len(), &mut |_| {})
+            .unwrap();
+
+        assert_eq!(requests.borrow()[1], [0x01, 0x02, 6, 0, 0, 0]);
+        assert_eq!(requests.borrow()[2], [&[0x08][..], &image].concat());
+        assert_eq!(report.bytes_sent, 6);
+    }
+
+    #[test]
+    fn test_send_bin_word_aligned_objects() {
+        let image: Vec<u8> = (0..18).collect();
+        let mut padded = image.clone();
+        padded.extend_from_slice(&[0xff, 0xff]);
+        let mut responses = vec![select_ok(10, 0, 0)];
+        for end in &[8, 16, 20] {
+            responses.push(ok(OpCode::CreateObject, &[]));
+            responses.push(crc_ok(*end, crc32fast::hash(&padded[..*end as usize])));
+            responses.push(ok(OpCode::Execute, &[]));
+        }
+        responses.push(crc_ok(20, crc32fast::hash(&padded)));
+        let (mut conn, requests) = connect(64, responses);
+        let report = conn
+            .send_bin_from_reader(&image[..], image.len(), &mut |_| {})
+            .unwrap();
+
+        assert_eq!(report.chunks, 3);
+        let sizes = requests
+            .borrow()
+            .iter()
+            .filter(|request| request.starts_with(&[0x01, 0x02]))
+            .map(|request| u32::from_le_bytes(request[2..6].try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![8, 8, 4]);
+    }
+
+    #[test]
+    fn test_send_bin_mtu_fallback() {
+        let image = [0x55; 128];
+        let (mut conn, requests) = connect(
+            128,
+            vec![
+                select_ok(128, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(128, !crc32fast::hash(&image)),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(128, crc32fast::hash(&image)),
+                ok(OpCode::Execute, &[]),
+                crc_ok(128, crc32fast::hash(&image)),
+            ],
+        );
+        conn.set_chunk_retries(1);
+        conn.set_mtu_fallback_after(1);
+        conn.send_bin(&image, &mut |_| {}).unwrap();
+
+        let write_sizes = requests
+            .borrow()
+            .iter()
+            .filter(|request| request[0] == OpCode::Write as u8)
+            .map(|request| request.len() - 1)
+            .collect::<Vec<_>>();
+        assert_eq!(write_sizes, vec![127, 1, 63, 63, 2]);
+    }
+
+    #[test]
+    fn test_reduced_chunk_size() {
+        assert_eq!(reduced_chunk_size(127), Some(63));
+        assert_eq!(reduced_chunk_size(63), Some(32));
+        assert_eq!(reduced_chunk_size(32), None);
+        assert_eq!(reduced_chunk_size(20), None);
+    }
+
+    #[test]
+    fn test_send_bin_crc_mismatch_location() {
+        let image: Vec<u8> = (0..16).collect();
+        let (mut conn, _) = connect(
+            64,
+            vec![
+                select_ok(8, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&image[..8])),
+                ok(OpCode::Execute, &[]),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(16, !crc32fast::hash(&image)),
+            ],
+        );
+        let err = conn.send_bin(&image, &mut |_| {}).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                DfuError::Crc {
+                    object: Some(1),
+                    offset: Some(8),
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+        assert!(err.to_string().ends_with(" in object 1 at offset 8"));
+    }
+
+    #[test]
+    fn test_send_bin_dropped_object() {
+        let image: Vec<u8> = (0..16).collect();
+        let (mut conn, _) = connect(
+            64,
+            vec![
+                select_ok(8, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&image[..8])),
+                ok(OpCode::Execute, &[]),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(16, crc32fast::hash(&image)),
+                ok(OpCode::Execute, &[]),
+                // The device lost the second object after executing it.
+                crc_ok(8, crc32fast::hash(&image[..8])),
+            ],
+        );
+        let err = conn.send_bin(&image, &mut |_| {}).unwrap_err();
+        assert!(matches!(err, DfuError::Crc { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_send_bin_cancelled() {
+        let image: Vec<u8> = (0..16).collect();
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(8, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&image[..8])),
+                ok(OpCode::Execute, &[]),
+            ],
+        );
+        let cancel = Arc::new(AtomicBool::new(false));
+        conn.set_cancel_flag(Some(cancel.clone()));
+
+        // Cancel after the first object.
+        let err = conn
+            .send_bin(&image, &mut |_| cancel.store(true, Ordering::Relaxed))
+            .unwrap_err();
+        assert!(matches!(err, DfuError::Cancelled), "{:?}", err);
+        assert_eq!(requests.borrow().last().unwrap(), &vec![0x0C]);
+        assert_eq!(
+            requests
+                .borrow()
+                .iter()
+                .filter(|request| request[0] == 0x01)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_send_bin_tiny_max_size() {
+        let (mut conn, _) = connect(64, vec![select_ok(3, 0, 0)]);
+        let err = conn.send_bin(&[0; 4], &mut |_| {}).unwrap_err();
+        assert!(matches!(err, DfuError::MalformedResponse(_)), "{:?}", err);
+
+        // A size of 0 must not reach the object reader, which would never make progress.
+        let (mut conn, requests) = connect(64, vec![select_ok(0, 0, 0)]);
+        let err = conn.send_bin(&[0; 4], &mut |_| {}).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "malformed response (maximum data object size of 0 bytes is too small)"
+        );
+        assert_eq!(requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_send_bin_resumes_partial_object() {
+        let image: Vec<u8> = (0..16).collect();
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                // The device holds the first object and half of the second one.
+                select_ok(8, 12, crc32fast::hash(&image[..12])),
+                crc_ok(16, crc32fast::hash(&image)),
+                ok(OpCode::Execute, &[]),
+                crc_ok(16, crc32fast::hash(&image)),
+            ],
+        );
+        conn.set_resume(true);
+        let report = conn.send_bin(&image, &mut |_| {}).unwrap();
+
+        assert_eq!(report.bytes_sent, 0);
+        assert_eq!(report.final_crc, crc32fast::hash(&image));
+        assert_eq!(
+            requests.borrow()[1..],
+            [
+                vec![0x08, 12, 13, 14, 15],
+                vec![0x03],
+                vec![0x04],
+                vec![0x03]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_bin_resend_corrupt_object() {
+        let image: Vec<u8> = (0..16).collect();
+        let (mut conn, requests) = connect(
+            64,
+            vec![
+                select_ok(8, 12, 0xdead_beef_u32),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(16, crc32fast::hash(&image)),
+                ok(OpCode::Execute, &[]),
+                crc_ok(16, crc32fast::hash(&image)),
+            ],
+        );
+        conn.set_resume(true);
+        let report = conn.send_bin(&image, &mut |_| {}).unwrap();
+
+        // The second object is sent again from its start.
+        assert_eq!(report.bytes_sent, 8);
+        assert_eq!(requests.borrow()[1], vec![0x01, 0x02, 8, 0, 0, 0]);
+        assert_eq!(requests.borrow()[2], [&[0x08][..], &image[8..]].concat());
+    }
+
+    #[test]
+    fn test_compare_object() {
+        let data: Vec<u8> = (0..16).collect();
+        let compare = |offset: usize, crc, algorithm| {
+            let select = SelectResponse {
+                max_size: 8,
+                offset: offset as u32,
+                crc,
+            };
+            compare_object(&data[..], 16, &select, algorithm).unwrap()
+        };
+        let crc32 = CrcAlgorithm::Crc32;
+
+        let complete = compare(16, crc32fast::hash(&data), crc32);
+        assert!(complete.is_complete());
+
+        let partial = compare(12, crc32fast::hash(&data[..12]), crc32);
+        assert!(partial.crc_matches);
+        assert!(!partial.is_complete());
+
+        let mismatch = compare(12, 0xdead_beef, crc32);
+        assert!(!mismatch.crc_matches);
+
+        // The device holds more data than the package contains.
+        let longer = compare(20, crc32fast::hash(&data), crc32);
+        assert!(!longer.crc_matches);
+
+        let crc32c = CrcAlgorithm::Crc32c;
+        assert!(compare(16, crc32c.update(0, &data), crc32c).is_complete());
+        assert!(!compare(16, crc32fast::hash(&data), crc32c).crc_matches);
+    }
+
+    #[test]
+    fn test_check_crc() {
+        let (conn, _) = connect(64, vec![]);
+        let data = [0xde, 0xad, 0xbe, 0xef];
+        let crc = crc32fast::hash(&data);
+        assert_eq!(conn.inner.check_crc(&data, crc, 0).unwrap(), crc);
+
+        // The CRC continues from `initial`.
+        let (first, second) = data.split_at(1);
+        let initial = crc32fast::hash(first);
+        assert_eq!(conn.inner.check_crc(second, crc, initial).unwrap(), crc);
+
+        let err = conn.inner.check_crc(&data, crc ^ 1, 0).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                DfuError::Crc { expected, received, .. } if expected == crc && received == crc ^ 1
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_stream_object_data_chunks() {
+        // The mock transport does not add any framing, so every frame (including the opcode) can
+        // be as large as the MTU.
+        let data = (0..20).collect::<Vec<u8>>();
+        let (mut conn, requests) = connect(10, vec![]);
+        conn.stream_object_data(&data, 0, 0).unwrap();
+
+        let requests = requests.borrow();
+        assert_eq!(requests.len(), 3);
+        let mut streamed = Vec::new();
+        for (request, len) in requests.iter().zip(vec![9, 9, 2]) {
+            assert_eq!(request[0], OpCode::Write as u8);
+            assert_eq!(request.len() - 1, len);
+            streamed.extend_from_slice(&request[1..]);
+        }
+        assert_eq!(streamed, data);
+    }
+
+    #[test]
+    fn test_stream_object_data_worst_case_slip() {
+        // Every byte has to be escaped, so each frame doubles in size when SLIP encoded.
+        for &byte in &[0xC0, 0xDB] {
+            let data = vec![byte; 3000];
+            // 13 is the smallest MTU that leaves room for `MIN_FRAME_SIZE` bytes after encoding.
+            for &mtu in &[13, 14, 23, 64, 65, 131, 247, 2048] {
+                let mut transport = MockTransport::new(vec![]);
+                transport.slip_framing = true;
+                let requests = transport.requests.clone();
+                let mut inner = Connection::new(Box::new(transport) as Box<dyn Transport>);
+                inner.mtu = mtu;
+                let mut conn = BootloaderConnection { inner };
+                conn.stream_object_data(&data, 0, 0).unwrap();
+
+                let mut streamed = Vec::new();
+                for request in requests.borrow().iter() {
+                    let mut encoded = Vec::new();
+                    slip::encode_frame(request, &mut encoded).unwrap();
+                    assert!(
+                        encoded.len() <= usize::from(mtu),
+                        "MTU {}: {} byte frame encodes to {} bytes",
+                        mtu,
+                        request.len(),
+                        encoded.len()
+                    );
+                    assert_eq!(request[0], OpCode::Write as u8);
+                    streamed.extend_from_slice(&request[1..]);
+                }
+                assert_eq!(streamed, data, "MTU {}", mtu);
+            }
+        }
+    }
+
+    fn receipt(offset: u32, crc: u32) -> io::Result<Vec<u8>> {
+        let mut payload = offset.to_le_bytes().to_vec();
+        payload.extend_from_slice(&crc.to_le_bytes());
+        ok(OpCode::Crc, &payload)
+    }
+
+    #[test]
+    fn test_stream_object_data_receipts() {
+        let data = (0..20).collect::<Vec<u8>>();
+        let initial_crc = crc32fast::hash(&[0xAA; 4]);
+        let (mut conn, requests) = connect(
+            10,
+            vec![
+                receipt(4 + 9, CrcAlgorithm::Crc32.update(initial_crc, &data[..9])),
+                receipt(4 + 18, CrcAlgorithm::Crc32.update(initial_crc, &data[..18])),
+            ],
+        );
+        conn.inner.prn = 1;
+        conn.stream_object_data(&data[..18], 4, initial_crc)
+            .unwrap();
+
+        assert_eq!(requests.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_stream_object_data_receipt_crc_mismatch() {
+        let data = (0..20).collect::<Vec<u8>>();
+        let crc = crc32fast::hash(&data[..18]);
+        let (mut conn, requests) = connect(10, vec![receipt(18, crc ^ 1)]);
+        conn.inner.prn = 2;
+        let err = conn.stream_object_data(&data, 0, 0).unwrap_err();
+
+        assert!(
+            matches!(
+                err,
+                DfuError::Crc { expected, received, .. } if expected == crc && received == crc ^ 1
+            ),
+            "{:?}",
+            err
+        );
+        // The rest of the data is not sent after a bad receipt.
+        assert_eq!(requests.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_stream_object_data_lost_write() {
+        let data = (0..20).collect::<Vec<u8>>();
+        // The device only got the first of two writes, so its CRC is right for what it has.
+        let (mut conn, _) = connect(10, vec![receipt(9, crc32fast::hash(&data[..9]))]);
+        conn.inner.prn = 2;
+        let err = conn.stream_object_data(&data, 0, 0).unwrap_err();
+
+        assert!(
+            matches!(
+                err,
+                DfuError::OffsetMismatch {
+                    expected: 18,
+                    received: 9
+                }
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_send_bin_with_receipts() {
+        let image = (0..16).collect::<Vec<u8>>();
+        let crc = crc32fast::hash(&image[..9]).to_le_bytes();
+        let (mut conn, requests) = connect(
+            10,
+            vec![
+                select_ok(64, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                // Receipts look exactly like a response to a CRC request.
+                Ok(vec![
+                    0x60, 0x03, 0x01, 9, 0, 0, 0, crc[0], crc[1], crc[2], crc[3],
+                ]),
+                receipt(16, crc32fast::hash(&image)),
+                crc_ok(16, crc32fast::hash(&image)),
+                ok(OpCode::Execute, &[]),
+                crc_ok(16, crc32fast::hash(&image)),
+            ],
+        );
+        conn.inner.prn = 1;
+        let report = conn
+            .send_bin_from_reader(&image[..], image.len(), &mut |_| {})
+            .unwrap();
+
+        assert_eq!(report.bytes_sent, 16);
+        assert_eq!(requests.borrow()[2], [&[0x08][..], &image[..9]].concat());
+        assert_eq!(requests.borrow()[3], [&[0x08][..], &image[9..]].concat());
+    }
+
+    #[test]
+    fn test_error_propagation() {
+        let (mut conn, _) = connect(
+            64,
+            vec![
+                select_ok(512, 0, 0),
+                Ok(vec![
+                    OpCode::Response as u8,
+                    OpCode::CreateObject as u8,
+                    ResultCode::InsufficientResources as u8,
+                ]),
+            ],
+        );
+        let err = conn.send_dat(&[1, 2, 3, 4]).unwrap_err();
+        assert!(
+            matches!(
+                err.root(),
+                DfuError::Protocol {
+                    opcode: OpCode::CreateObject,
+                    result_code: ResultCode::InsufficientResources,
+                    ext_error: None,
+                }
+            ),
+            "{:?}",
+            err
+        );
+
+        // Transport errors are passed through as well.
+        let (mut conn, _) = connect(64, vec![timeout()]);
+        let err = conn.send_dat(&[1, 2, 3, 4]).unwrap_err();
+        assert!(
+            matches!(err.root(), DfuError::Serial(e) if e.kind() == io::ErrorKind::TimedOut),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_error_context() {
+        let image: Vec<u8> = (0..16).collect();
+        let (mut conn, _) = connect(
+            64,
+            vec![
+                select_ok(8, 0, 0),
+                ok(OpCode::CreateObject, &[]),
+                crc_ok(8, crc32fast::hash(&image[..8])),
+                ok(OpCode::Execute, &[]),
+                Ok(vec![
+                    OpCode::Response as u8,
+                    OpCode::CreateObject as u8,
+                    ResultCode::InsufficientResources as u8,
+                ]),
+            ],
+        );
+        let err = conn.send_bin(&image, &mut |_| {}).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("while creating data object at offset 8: CreateObject request failed"),
+            "{}",
+            err
+        );
+        assert!(matches!(err.root(), DfuError::Protocol { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_fetch_firmware_versions() {
+        let firmware = |fw_type: FirmwareType, version: u32| {
+            let mut payload = vec![u8::from(fw_type)];
+            for value in &[version, 0x1000, 0x2000] {
+                payload.extend_from_slice(&value.to_le_bytes());
+            }
+            ok(OpCode::FirmwareVersionGet, &payload)
+        };
+        let (mut conn, _) = connect(
+            64,
+            vec![
+                firmware(FirmwareType::Bootloader, 2),
+                firmware(FirmwareType::Application, 0),
+                firmware(FirmwareType::Unknown, 0),
+            ],
+        );
+        let installed = conn.fetch_firmware_versions().unwrap();
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed[1].fw_type, FirmwareType::Application);
+
+        let image = [1, 2, 3, 4];
+        let images = [FirmwareImage {
+            kind: ImageKind::Application,
+            dat_file: "app.dat".to_string(),
+            dat: init_packet::build_init_packet(&image),
+            bin_file: "app.bin".to_string(),
+            bin_len: image.len(),
+            init_packet_data: None,
+        }];
+        assert!(is_current(&images, &installed).unwrap());
+        assert!(!is_current(&images, &installed[..1]).unwrap());
+
+        // Bootloaders that don't support the request don't report any firmware.
+        let (mut conn, _) = connect(
+            64,
+            vec![Ok(vec![
+                OpCode::Response as u8,
+                OpCode::FirmwareVersionGet as u8,
+                ResultCode::OpCodeNotSupported as u8,
+            ])],
+        );
+        assert!(conn.fetch_firmware_versions().unwrap().is_empty());
+
+        let (mut conn, _) = connect(
+            64,
+            vec![Ok(vec![
+                OpCode::Response as u8,
+                OpCode::FirmwareVersionGet as u8,
+                ResultCode::OpCodeNotSupported as u8,
+            ])],
+        );
+        assert_eq!(conn.firmware_info().unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_retry() {
+        let (mut conn, requests) =
+            connect(64, vec![timeout(), timeout(), ok(OpCode::Execute, &[])]);
+        conn.set_read_retries(2);
+        conn.execute().unwrap();
+        // Only the read is retried, the request is sent once.
+        assert_eq!(requests.borrow().len(), 1);
+
+        let (mut conn, _) = connect(64, vec![timeout(), timeout()]);
+        conn.set_read_retries(1);
+        let err = conn.execute().unwrap_err();
+        assert!(
+            matches!(&err, DfuError::Serial(e) if e.kind() == io::ErrorKind::TimedOut),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_read_retry_delay() {
+        assert_eq!(read_retry_delay(1), READ_RETRY_BASE_DELAY);
+        assert_eq!(read_retry_delay(3), READ_RETRY_BASE_DELAY * 4);
+    }
+
+    /// Connects via `transport`, retrying the handshake up to `handshake_retries` times.
+    fn handshake(transport: MockTransport, handshake_retries: u32) -> Result<BootloaderConnection> {
+        let options = UpdateOptions {
+            handshake_retries,
+            ..UpdateOptions::default()
+        };
+        BootloaderConnection::connect_with_options(Box::new(transport), &options)
+    }
+
+    #[test]
+    fn test_handshake_retry() {
+        let transport = MockTransport::new(vec![
+            timeout(),
+            timeout(),
+            Ok(vec![0x60, 0x00, 0x01, PROTOCOL_VERSION]),
+            Ok(vec![0x60, 0x07, 0x01, 0x83, 0x00]),
+        ]);
+        let conn = handshake(transport, 2).unwrap();
+        assert_eq!(conn.inner.mtu, 0x83);
+    }
+
+    #[test]
+    fn test_handshake_retries_exhausted() {
+        let transport = MockTransport::new(vec![timeout(), timeout(), timeout()]);
+        let err = handshake(transport, 2).err().unwrap();
+        assert!(matches!(err, DfuError::Serial(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_handshake_tiny_mtu() {
+        for &mtu in &[0u16, 1, 5] {
+            let transport = MockTransport::new(vec![
+                ok(OpCode::ProtocolVersion, &[PROTOCOL_VERSION]),
+                ok(OpCode::MtuGet, &mtu.to_le_bytes()),
+            ]);
+            let err = handshake(transport, 0).err().unwrap();
+            assert!(
+                matches!(err, DfuError::UnsupportedMtu(m) if m == mtu),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_handshake_clamps_mtu() {
+        let transport = MockTransport::new(vec![
+            ok(OpCode::ProtocolVersion, &[PROTOCOL_VERSION]),
+            ok(OpCode::MtuGet, &0xffffu16.to_le_bytes()),
+        ]);
+        let mut conn = handshake(transport, 0).unwrap();
+        assert_eq!(conn.inner.mtu, 0xffff);
+        assert_eq!(conn.inner.chunk_size(), usize::from(DEFAULT_MAX_MTU) - 1);
+
+        conn.set_max_mtu(64).unwrap();
+        assert_eq!(conn.inner.chunk_size(), 63);
+        let err = conn.set_max_mtu(5).unwrap_err();
+        assert!(matches!(err, DfuError::UnsupportedMtu(5)), "{:?}", err);
+        assert_eq!(conn.inner.chunk_size(), 63);
+    }
+
+    #[test]
+    fn test_handshake_no_retry_on_version_mismatch() {
+        let transport = MockTransport::new(vec![Ok(vec![0x60, 0x00, 0x01, 0xff])]);
+        let err = handshake(transport, 2).err().unwrap();
+        assert!(
+            matches!(err, DfuError::UnsupportedProtocolVersion { got: 0xff, .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_handshake_accepted_version() {
+        let transport = MockTransport::new(vec![
+            Ok(vec![0x60, 0x00, 0x01, 0x02]),
+            ok(OpCode::MtuGet, &64u16.to_le_bytes()),
+        ]);
+        let options = UpdateOptions {
+            handshake_retries: 0,
+            accepted_protocol_versions: vec![2],
+            ..UpdateOptions::default()
+        };
+        let conn =
+            BootloaderConnection::connect_with_options(Box::new(transport), &options).unwrap();
+        assert_eq!(conn.inner.mtu, 64);
+    }
+
+    #[test]
+    fn test_handshake_forced_version() {
+        let transport = MockTransport::new(vec![
+            Ok(vec![0x60, 0x00, 0x01, 0x07]),
+            ok(OpCode::MtuGet, &64u16.to_le_bytes()),
+        ]);
+        let options = UpdateOptions {
+            handshake_retries: 0,
+            force_protocol_version: true,
+            ..UpdateOptions::default()
+        };
+        let conn =
+            BootloaderConnection::connect_with_options(Box::new(transport), &options).unwrap();
+        assert_eq!(conn.inner.mtu, 64);
+    }
+
+    fn crc_error() -> DfuError {
+        DfuError::Crc {
+            expected: 1,
+            received: 2,
+            object: None,
+            offset: None,
+        }
+    }
+
+    #[test]
+    fn test_manifest_init_packet_data() {
+        let manifest: OuterManifest = serde_json::from_str(
+            r#"{
+                "manifest": {
+                    "application": {
+                        "bin_file": "app.bin",
+                        "dat_file": "app.dat",
+                        "init_packet_data": {
+                            "application_version": 4,
+                            "device_revision": 65535,
+                            "device_type": 82,
+                            "firmware_crc16": 47837,
+                            "softdevice_req": [182, 183]
+                        }
+                    },
+                    "softdevice": {
+                        "bin_file": "sd.bin",
+                        "dat_file": "sd.dat"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let manifest = manifest.manifest;
+
+        let data = manifest
+            .application
+            .as_ref()
+            .unwrap()
+            .init_packet_data
+            .as_ref();
+        assert_eq!(
+            data,
+            Some(&InitPacketData {
+                application_version: Some(4),
+                device_revision: Some(0xffff),
+                device_type: Some(0x52),
+                firmware_crc16: Some(0xbadd),
+                firmware_hash: None,
+                softdevice_req: vec![0xb6, 0xb7],
+            })
+        );
+        assert_eq!(
+            manifest.describe(),
+            "softdevice: sd.dat, sd.bin\n\
+             application: app.dat, app.bin (application version 4, device type 0x0052, \
+             device revision 0xffff, SoftDevice 0x00b6/0x00b7, CRC16 0xbadd)"
+        );
+    }
+
+    #[test]
+    fn test_inspect_package() {
+        let bin = [1, 2, 3, 4, 5];
+        let path = std::env::temp_dir().join(format!("nrfdfu-inspect-{}.zip", std::process::id()));
+        let zip = zip_file::zip_package(&[
+            (
+                "manifest.json",
+                br#"{"manifest":{"dfu_version":0.5,"application":{"bin_file":"app.bin","dat_file":"app.dat","init_packet_data":{"application_version":4}}}}"#,
+            ),
+            ("app.dat", &init_packet::build_init_packet(&bin)),
+            ("app.bin", &bin),
+        ]);
+        std::fs::write(&path, zip.into_inner()).unwrap();
+
+        let info = inspect_package(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(info.manifest_file, "manifest.json");
+        assert_eq!(info.dfu_version, Some(0.5));
+        assert_eq!(info.images.len(), 1);
+        let image = &info.images[0];
+        assert_eq!(image.kind, ImageKind::Application);
+        assert_eq!(image.bin_len, bin.len());
+        assert_eq!(image.padded_len, 8);
+        assert_eq!(image.bin_crc, crc32fast::hash(&bin));
+        assert_eq!(
+            image.init_packet_data.as_ref().unwrap().application_version,
+            Some(4)
+        );
+        assert_eq!(image.init_packet.hw_version, Some(52));
+        assert_eq!(image.init_packet.image_size, 5);
+
+        assert!(inspect_package("does-not-exist.zip").is_err());
+    }
+
+    #[test]
+    fn test_select_images() {
+        let bin = [1, 2, 3, 4];
+        let dat = init_packet::build_init_packet(&bin);
+        let package = || {
+            let mut package = zip_file::read_bytes(&dat, &bin).unwrap();
+            package.images.insert(
+                0,
+                FirmwareImage {
+                    kind: ImageKind::Softdevice,
+                    dat_file: "sd.dat".to_string(),
+                    dat: dat.clone(),
+                    bin_file: "sd.bin".to_string(),
+                    bin_len: bin.len(),
+                    init_packet_data: None,
+                },
+            );
+            package
+        };
+        let kinds = |package: &DfuPackage<_>| -> Vec<ImageKind> {
+            package.images.iter().map(|image| image.kind).collect()
+        };
+
+        let mut all = package();
+        select_images(&mut all, &[]).unwrap();
+        assert_eq!(
+            kinds(&all),
+            vec![ImageKind::Softdevice, ImageKind::Application]
+        );
+
+        let mut app = package();
+        select_images(&mut app, &[ImageKind::Application]).unwrap();
+        assert_eq!(kinds(&app), vec![ImageKind::Application]);
+
+        let err = select_images(&mut package(), &[ImageKind::Bootloader]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid DFU package: package contains no bootloader image \
+             (it contains: softdevice, application)"
+        );
+    }
+
+    #[test]
+    fn test_check_sd_req() {
+        let image = |kind: ImageKind, sd_req: &[u32]| FirmwareImage {
+            kind,
+            dat_file: "app.dat".to_string(),
+            dat: init_packet::build_init_packet_with_sd_req(&[1, 2, 3, 4], sd_req),
+            bin_file: "app.bin".to_string(),
+            bin_len: 4,
+            init_packet_data: None,
+        };
+        let firmware = |fw_type: FirmwareType, len: u32| FirmwareVersionResponse {
+            fw_type,
+            version: 7002000,
+            addr: 0x1000,
+            len,
+        };
+        let bootloader = firmware(FirmwareType::Bootloader, 0x6000);
+        let softdevice = firmware(FirmwareType::Softdevice, 0x26000);
+
+        // The device is only asked about its SoftDevice if there is something to check.
+        let app = image(ImageKind::Application, &[0x0100]);
+        assert!(needs_sd_check(&[app]).unwrap());
+        assert!(!needs_sd_check(&[image(ImageKind::Application, &[])]).unwrap());
+        assert!(!needs_sd_check(&[image(ImageKind::Application, &[SD_REQ_ANY])]).unwrap());
+        let with_softdevice = [
+            image(ImageKind::Softdevice, &[]),
+            image(ImageKind::Application, &[0x0100]),
+        ];
+        assert!(!needs_sd_check(&with_softdevice).unwrap());
+
+        let app = [image(ImageKind::Application, &[0x0100, 0x0101])];
+        check_sd_req(&app, &[bootloader, softdevice], false).unwrap();
+        check_sd_req(&app, &[bootloader], true).unwrap();
+        let err = check_sd_req(&app, &[bootloader], false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "incompatible SoftDevice: application image requires SoftDevice 0x0100/0x0101, \
+             but the device has none"
+        );
+        // An empty SoftDevice region does not count.
+        let empty = firmware(FirmwareType::Softdevice, 0);
+        assert!(check_sd_req(&app, &[bootloader, empty], false).is_err());
+        // Bootloaders that don't report their firmware can't be checked.
+        check_sd_req(&app, &[], false).unwrap();
+
+        let standalone = [image(ImageKind::Application, &[SD_REQ_NONE])];
+        check_sd_req(&standalone, &[bootloader], false).unwrap();
+        let err = check_sd_req(&standalone, &[bootloader, softdevice], false).unwrap_err();
+        assert!(matches!(err, DfuError::SoftDeviceMismatch(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_format_throughput() {
+        assert_eq!(
+            format_throughput(40960, 262144, 40960, Duration::from_secs(5)),
+            "sent 40960/262144 bytes (15%), 8.0 KiB/s, ETA 27s"
+        );
+        // Resumed transfer: only the bytes sent in this run count towards the speed.
+        assert_eq!(
+            format_throughput(8192, 16384, 4096, Duration::from_secs(1)),
+            "sent 8192/16384 bytes (50%), 4.0 KiB/s, ETA 2s"
+        );
+        // Nothing sent yet, so no ETA can be given.
+        assert_eq!(
+            format_throughput(0, 1024, 0, Duration::from_secs(1)),
+            "sent 0/1024 bytes (0%), 0.0 KiB/s"
+        );
+    }
+
+    #[test]
+    fn test_retry_after_crc_mismatch() {
+        let mut calls = 0;
+        let result = block_on(async {
+            retry_on_crc_error!(3, |attempt| async {
+                calls += 1;
+                if attempt == 0 {
+                    Err(crc_error())
+                } else {
+                    Ok(0xdead_beef_u32)
+                }
+            })
+        });
+        assert_eq!(result.unwrap(), 0xdead_beef);
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn test_retries_exhausted() {
+        let mut calls = 0;
+        let result: Result<()> = block_on(async {
+            retry_on_crc_error!(3, |attempt| async {
+                calls += 1;
+                Err(crc_error())
+            })
+        });
+        assert!(matches!(result, Err(DfuError::Crc { .. })));
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn test_no_retry_on_other_errors() {
+        let mut calls = 0;
+        let result: Result<()> = block_on(async {
+            retry_on_crc_error!(3, |attempt| async {
+                calls += 1;
+                Err(DfuError::Ping("timeout".to_string()))
+            })
+        });
+        assert!(matches!(result, Err(DfuError::Ping(_))));
+        assert_eq!(calls, 1);
+    }
+}
@@ -41,3 +41,39 @@ macro_rules! primitive_enum {
         }
     }
 }
+
+/// Creates a `tracing` span named `$name` with the given fields, to be entered with `in_span`.
+///
+/// Expands to a placeholder unless the `tracing` feature is enabled.
+macro_rules! span {
+    ($name:literal $(, $field:ident = $value:expr)* $(,)?) => {{
+        #[cfg(feature = "tracing")]
+        let span = ::tracing::debug_span!($name $(, $field = $value)*);
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::connection::Span;
+        span
+    }};
+}
+
+/// Awaits the future `async $attempt` until it succeeds, but at most `$retries + 1` times, with
+/// `$n` bound to the number of the current retry (0 for the first attempt).
+///
+/// Only CRC and offset mismatches are retried; any other error is returned immediately. This is a
+/// macro rather than a function taking a closure so that the attempts can borrow the connection
+/// mutably.
+macro_rules! retry_on_crc_error {
+    ($retries:expr, |$n:ident| async $attempt:block) => {{
+        let retries: u32 = $retries;
+        let mut $n: u32 = 0;
+        loop {
+            match async $attempt.await {
+                Err($crate::DfuError::Crc { .. } | $crate::DfuError::OffsetMismatch { .. })
+                    if $n < retries =>
+                {
+                    $n += 1
+                }
+                result => break result,
+            }
+        }
+    }};
+}
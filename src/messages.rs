@@ -1,14 +1,13 @@
-use std::{
-    error::Error,
-    fmt,
-    io::{self, Read, Write},
-};
+use std::fmt;
+use std::io::{self, Read, Write};
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 
+use crate::DfuError;
+
 // opcodes
 // note: incomplete; only contains opcodes that we currently use
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OpCode {
     ProtocolVersion = 0x00,
     CreateObject = 0x01,
@@ -20,11 +19,13 @@ pub enum OpCode {
     Write = 0x08,
     Ping = 0x09,
     HardwareVersionGet = 0x0A,
+    FirmwareVersionGet = 0x0B,
+    Abort = 0x0C,
     Response = 0x60, // marks the start of a response message
 }
 
 primitive_enum! {
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum ResultCode(u8) {
         /// Invalid request opcode.
         Invalid = 0x00,
@@ -52,7 +53,7 @@ primitive_enum! {
 }
 
 primitive_enum! {
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum ExtError(u8) {
         /// No extended error code set. This should never appear.
         NoError = 0x00,
@@ -74,53 +75,50 @@ primitive_enum! {
     }
 }
 
-/// An error code returned by the bootloader.
-#[derive(Debug)]
-pub struct DfuError {
-    code: ResultCode,
-    ext_error: Option<ExtError>,
+/// Returns the name of an error code returned by the bootloader, e.g. `ExtError(HashFailed)`.
+pub fn error_name(code: ResultCode, ext_error: Option<ExtError>) -> String {
+    match ext_error {
+        Some(ext_error) => format!("{:?}({:?})", code, ext_error),
+        None => format!("{:?}", code),
+    }
 }
 
-impl fmt::Display for DfuError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = match self.ext_error {
-            Some(ExtError::NoError) => "no extended error set",
-            Some(ExtError::InvalidErrorCode) => "invalid extended error code",
-            Some(ExtError::WrongCommandFormat) => "incorrect command format",
-            Some(ExtError::UnknownCommand) => "unknown command",
-            Some(ExtError::InitCommandInvalid) => "initialization command invalid",
-            Some(ExtError::FwVersionFailure) => {
-                "invalid firmware version (possible downgrade attempted)"
+/// Returns a human-readable description of an error code returned by the bootloader.
+pub fn describe_error(code: ResultCode, ext_error: Option<ExtError>) -> &'static str {
+    match ext_error {
+        Some(ExtError::NoError) => "no extended error set",
+        Some(ExtError::InvalidErrorCode) => "invalid extended error code",
+        Some(ExtError::WrongCommandFormat) => "incorrect command format",
+        Some(ExtError::UnknownCommand) => "unknown command",
+        Some(ExtError::InitCommandInvalid) => "initialization command invalid",
+        Some(ExtError::FwVersionFailure) => {
+            "invalid firmware version (possible downgrade attempted)"
+        }
+        Some(ExtError::HwVersionFailure) => "hardware version mismatch",
+        Some(ExtError::SdVersionFailure) => "firmware requires unavailable SoftDevice version",
+        Some(ExtError::SignatureMissing) => "missing image signature",
+        Some(ExtError::WrongHashType) => "unsupported hash type used in initialization command",
+        Some(ExtError::HashFailed) => "failed to compute firmware hash",
+        Some(ExtError::WrongSignatureType) => "unsupported signature type",
+        Some(ExtError::VerificationFailed) => "hash verification failed",
+        Some(ExtError::InsufficientSpace) => "insufficient space for firmware",
+        None => match code {
+            ResultCode::Invalid => "invalid request opcode",
+            ResultCode::Success => "success",
+            ResultCode::OpCodeNotSupported => "opcode not supported",
+            ResultCode::InvalidParameter => "missing or invalid request parameter",
+            ResultCode::InsufficientResources => "not enough memory to create object",
+            ResultCode::InvalidObject => "invalid data object",
+            ResultCode::UnsupportedType => "invalid object type for create object request",
+            ResultCode::OperationNotPermitted => {
+                "operation not permitted in the current state (was a valid init packet sent?)"
             }
-            Some(ExtError::HwVersionFailure) => "hardware version mismatch",
-            Some(ExtError::SdVersionFailure) => "firmware requires unavailable SoftDevice version",
-            Some(ExtError::SignatureMissing) => "missing image signature",
-            Some(ExtError::WrongHashType) => "unsupported hash type used in initialization command",
-            Some(ExtError::HashFailed) => "failed to compute firmware hash",
-            Some(ExtError::WrongSignatureType) => "unsupported signature type",
-            Some(ExtError::VerificationFailed) => "hash verification failed",
-            Some(ExtError::InsufficientSpace) => "insufficient space for firmware",
-            None => match self.code {
-                ResultCode::Invalid => "invalid request opcode",
-                ResultCode::Success => "success",
-                ResultCode::OpCodeNotSupported => "opcode not supported",
-                ResultCode::InvalidParameter => "missing or invalid request parameter",
-                ResultCode::InsufficientResources => "not enough memory to create object",
-                ResultCode::InvalidObject => "invalid data object",
-                ResultCode::UnsupportedType => "invalid object type for create object request",
-                ResultCode::OperationNotPermitted => "operation not permitted in the current state",
-                ResultCode::OperationFailed => "operation failed",
-                ResultCode::ExtError => {
-                    panic!("`EXT_ERROR` result code without extended error byte")
-                }
-            },
-        };
-        f.write_str(s)
+            ResultCode::OperationFailed => "operation failed",
+            ResultCode::ExtError => "extended error",
+        },
     }
 }
 
-impl Error for DfuError {}
-
 #[repr(u8)]
 #[derive(Copy, Clone)]
 pub enum ObjectType {
@@ -144,22 +142,26 @@ pub struct ProtocolVersionRequest;
 impl Request for ProtocolVersionRequest {
     const OPCODE: OpCode = OpCode::ProtocolVersion;
 
-    type Response = ProtocolVersionResponse;
+    type Response = ProtocolVersion;
 
     fn write_payload<W: Write>(&self, _writer: W) -> io::Result<()> {
         Ok(())
     }
 }
 
-pub struct ProtocolVersionResponse {
-    pub version: u8,
+/// Version of the DFU protocol spoken by the bootloader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion(pub u8);
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-impl Response for ProtocolVersionResponse {
+impl Response for ProtocolVersion {
     fn read_payload<R: Read>(mut reader: R) -> io::Result<Self> {
-        Ok(Self {
-            version: reader.read_u8()?,
-        })
+        Ok(Self(reader.read_u8()?))
     }
 }
 
@@ -168,24 +170,46 @@ pub struct HardwareVersionRequest;
 impl Request for HardwareVersionRequest {
     const OPCODE: OpCode = OpCode::HardwareVersionGet;
 
-    type Response = HardwareVersionResponse;
+    type Response = HardwareVersion;
 
     fn write_payload<W: Write>(&self, _writer: W) -> io::Result<()> {
         Ok(())
     }
 }
 
-#[derive(Debug)]
-pub struct HardwareVersionResponse {
-    // See FICR register docs
-    part: u32,
-    variant: u32,
-    rom_size: u32,
-    ram_size: u32,
-    rom_page_size: u32,
+/// Information about the device's chip, taken from its FICR registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HardwareVersion {
+    /// Part number, e.g. `0x52840` for the nRF52840.
+    pub part: u32,
+    /// Part variant, as 4 ASCII characters (e.g. `AAD0`) packed into a big-endian integer.
+    pub variant: u32,
+    /// Size of the flash memory in bytes.
+    pub rom_size: u32,
+    /// Size of the RAM in bytes.
+    pub ram_size: u32,
+    /// Size of a flash page in bytes.
+    pub rom_page_size: u32,
+}
+
+impl fmt::Display for HardwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "nRF{:x}", self.part)?;
+        let variant = self.variant.to_be_bytes();
+        if variant.iter().all(u8::is_ascii_alphanumeric) {
+            write!(f, " {}", String::from_utf8_lossy(&variant))?;
+        }
+        write!(
+            f,
+            ", {} KiB flash ({} KiB pages), {} KiB RAM",
+            self.rom_size / 1024,
+            self.rom_page_size / 1024,
+            self.ram_size / 1024
+        )
+    }
 }
 
-impl Response for HardwareVersionResponse {
+impl Response for HardwareVersion {
     fn read_payload<R: Read>(mut response_bytes: R) -> io::Result<Self> {
         Ok(Self {
             part: response_bytes.read_u32::<LE>()?,
@@ -197,6 +221,64 @@ impl Response for HardwareVersionResponse {
     }
 }
 
+/// Requests information about the firmware image with the given number.
+///
+/// The bootloader numbers its images as follows: 0 is the bootloader itself, followed by the
+/// SoftDevice (if present) and the application (if present).
+pub struct FirmwareVersionRequest(pub u8);
+
+impl Request for FirmwareVersionRequest {
+    const OPCODE: OpCode = OpCode::FirmwareVersionGet;
+
+    type Response = FirmwareVersionResponse;
+
+    fn write_payload<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u8(self.0)
+    }
+}
+
+primitive_enum! {
+    /// Kind of a firmware image installed on the device.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FirmwareType(u8) {
+        Softdevice = 0x00,
+        Application = 0x01,
+        Bootloader = 0x02,
+        /// There is no image with the requested number.
+        Unknown = 0xFF,
+    }
+}
+
+/// A firmware image installed on the device, as reported by the bootloader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareVersionResponse {
+    pub fw_type: FirmwareType,
+    /// Version of the image. For applications and bootloaders, this is the `fw_version` declared
+    /// by the init packet they were flashed with.
+    pub version: u32,
+    /// Start address of the image in flash.
+    pub addr: u32,
+    /// Size of the image in bytes.
+    pub len: u32,
+}
+
+impl Response for FirmwareVersionResponse {
+    fn read_payload<R: Read>(mut reader: R) -> io::Result<Self> {
+        let fw_type = reader.read_u8()?;
+        Ok(Self {
+            fw_type: FirmwareType::from_primitive(fw_type).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown firmware type 0x{:02x}", fw_type),
+                )
+            })?,
+            version: reader.read_u32::<LE>()?,
+            addr: reader.read_u32::<LE>()?,
+            len: reader.read_u32::<LE>()?,
+        })
+    }
+}
+
 pub struct PingRequest(pub u8);
 
 impl Request for PingRequest {
@@ -210,11 +292,16 @@ impl Request for PingRequest {
 }
 
 #[derive(Debug)]
-pub struct PingResponse(pub u8);
+pub struct PingResponse {
+    /// The ID sent in the `PingRequest`, echoed back by the device.
+    pub id: u8,
+}
 
 impl Response for PingResponse {
     fn read_payload<R: Read>(mut reader: R) -> io::Result<Self> {
-        Ok(Self(reader.read_u8()?))
+        Ok(Self {
+            id: reader.read_u8()?,
+        })
     }
 }
 
@@ -231,10 +318,23 @@ impl Request for SelectRequest {
     }
 }
 
-#[derive(Debug)]
+/// State of the selected object type, as reported by the device.
+///
+/// Returned by [`BootloaderConnection::select_object_command`] and
+/// [`BootloaderConnection::select_object_data`]. `offset` and `crc` describe the data the device
+/// already holds, which is what resuming and verifying an update are based on.
+///
+/// [`BootloaderConnection::select_object_command`]: crate::BootloaderConnection::select_object_command
+/// [`BootloaderConnection::select_object_data`]: crate::BootloaderConnection::select_object_data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SelectResponse {
+    /// Maximum size of a single object of this type.
     pub max_size: u32,
+    /// Number of bytes of this object type received so far, across all objects of the current
+    /// image.
     pub offset: u32,
+    /// CRC of the `offset` bytes received so far, computed with the bootloader's
+    /// [`CrcAlgorithm`](crate::CrcAlgorithm).
     pub crc: u32,
 }
 
@@ -336,8 +436,7 @@ impl Request for WriteRequest<'_> {
     type Response = WriteResponse;
 
     fn write_payload<W: Write>(&self, mut writer: W) -> io::Result<()> {
-        writer.write(self.request_payload)?;
-        Ok(())
+        writer.write_all(self.request_payload)
     }
 }
 
@@ -364,9 +463,12 @@ impl Request for CrcRequest {
     }
 }
 
-#[derive(Debug)]
+/// Progress of the current object type, as reported by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct CrcResponse {
+    /// Number of bytes received so far.
     pub offset: u32,
+    /// CRC32 of the bytes received so far.
     pub crc: u32,
 }
 
@@ -400,6 +502,28 @@ impl Response for ExecuteResponse {
     }
 }
 
+pub struct AbortRequest;
+
+impl Request for AbortRequest {
+    const OPCODE: OpCode = OpCode::Abort;
+
+    type Response = AbortResponse;
+
+    fn write_payload<W: Write>(&self, _writer: W) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// HACK: this is never used, the bootloader does not reliably respond to an abort request.
+#[derive(Debug)]
+pub enum AbortResponse {}
+
+impl Response for AbortResponse {
+    fn read_payload<R: Read>(_reader: R) -> io::Result<Self> {
+        unreachable!()
+    }
+}
+
 pub fn parse_response<R: Request>(buf: &[u8]) -> crate::Result<R::Response> {
     // Response format:
     // - Fixed byte 0x60
@@ -407,70 +531,189 @@ pub fn parse_response<R: Request>(buf: &[u8]) -> crate::Result<R::Response> {
     // - Response result code
     // - Response payload
     if buf.len() < 3 {
-        return Err(format!(
-            "truncated response (expected at least 3 bytes, got {})",
+        return Err(DfuError::MalformedResponse(format!(
+            "truncated response: expected at least 3 bytes, got {}",
             buf.len()
-        )
-        .into());
+        )));
     }
 
     if buf[0] != OpCode::Response as u8 {
-        return Err(format!(
-            "malformed response (expected nrf DFU response preamble 0x60, got 0x{:02x})",
+        return Err(DfuError::MalformedResponse(format!(
+            "expected nrf DFU response preamble 0x60, got 0x{:02x}",
             buf[0]
-        )
-        .into());
+        )));
     }
 
     if buf[1] != R::OPCODE as u8 {
-        return Err(format!(
-            "malformed response (expected echoed opcode {:?} (0x{:02x}), got 0x{:02x})",
+        return Err(DfuError::MalformedResponse(format!(
+            "expected echoed opcode {:?} (0x{:02x}), got 0x{:02x}",
             R::OPCODE,
             R::OPCODE as u8,
             buf[1]
-        )
-        .into());
+        )));
     }
 
-    let result: ResultCode = ResultCode::from_primitive(buf[2])
-        .ok_or_else(|| format!("malformed response (invalid result code 0x{:02x})", buf[2]))?;
+    let result: ResultCode = ResultCode::from_primitive(buf[2]).ok_or_else(|| {
+        DfuError::MalformedResponse(format!("invalid result code 0x{:02x}", buf[2]))
+    })?;
 
     match result {
         ResultCode::Success => {}
-        ResultCode::ExtError => return match buf.get(3) {
-            Some(byte) => {
-                let ext_error: ExtError = ExtError::from_primitive(*byte).ok_or_else(|| {
-                    format!(
-                        "malformed response (unknown extended error code 0x{:02x})",
-                        byte
-                    )
-                })?;
-
-                Err(DfuError {
-                    code: ResultCode::ExtError,
-                    ext_error: Some(ext_error),
+        ResultCode::ExtError => {
+            return match buf.get(3) {
+                Some(byte) => {
+                    let ext_error: ExtError = ExtError::from_primitive(*byte).ok_or_else(|| {
+                        DfuError::MalformedResponse(format!(
+                            "unknown extended error code 0x{:02x}",
+                            byte
+                        ))
+                    })?;
+
+                    Err(DfuError::Protocol {
+                        opcode: R::OPCODE,
+                        result_code: ResultCode::ExtError,
+                        ext_error: Some(ext_error),
+                    })
                 }
-                    .into())
-            }
-            None => {
-                Err("malformed response (missing extended error byte)".to_string().into())
+                None => Err(DfuError::MalformedResponse(
+                    "missing extended error byte".to_string(),
+                )),
             }
-        },
+        }
         code => {
-            return Err(DfuError {
-                code,
+            return Err(DfuError::Protocol {
+                opcode: R::OPCODE,
+                result_code: code,
                 ext_error: None,
-            }
-            .into())
+            })
         }
     }
 
     let mut response_bytes = &buf[3..];
-    let response = Response::read_payload(&mut response_bytes)?;
+    let response = Response::read_payload(&mut response_bytes)
+        .map_err(|e| DfuError::MalformedResponse(format!("invalid payload: {}", e)))?;
 
     if !response_bytes.is_empty() {
-        return Err("trailing bytes in response".to_string().into());
+        return Err(DfuError::MalformedResponse(
+            "trailing bytes in response".to_string(),
+        ));
     }
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_success() {
+        let response = parse_response::<PingRequest>(&[0x60, 0x09, 0x01, 0x2a]).unwrap();
+        assert_eq!(response.id, 0x2a);
+    }
+
+    #[test]
+    fn test_parse_firmware_version() {
+        let response = parse_response::<FirmwareVersionRequest>(&[
+            0x60, 0x0B, 0x01, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x10, 0x02, 0x00, 0x00, 0x80,
+            0x00, 0x00,
+        ])
+        .unwrap();
+        assert_eq!(
+            response,
+            FirmwareVersionResponse {
+                fw_type: FirmwareType::Application,
+                version: 3,
+                addr: 0x21000,
+                len: 0x8000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_select() {
+        let response = parse_response::<SelectRequest>(&[
+            0x60, 0x06, 0x01, 0x00, 0x10, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0xef, 0xbe, 0xad,
+            0xde,
+        ])
+        .unwrap();
+        assert_eq!(
+            response,
+            SelectResponse {
+                max_size: 0x1000,
+                offset: 0x1234,
+                crc: 0xdead_beef,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_error_code() {
+        let err = parse_response::<CrcRequest>(&[0x60, 0x03, 0x08]).unwrap_err();
+        assert!(matches!(
+            err,
+            DfuError::Protocol {
+                opcode: OpCode::Crc,
+                result_code: ResultCode::OperationNotPermitted,
+                ext_error: None,
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Crc request failed with OperationNotPermitted: operation not permitted in the \
+             current state (was a valid init packet sent?)"
+        );
+    }
+
+    #[test]
+    fn test_parse_ext_error() {
+        let err = parse_response::<CrcRequest>(&[0x60, 0x03, 0x0B, 0x0A]).unwrap_err();
+        assert!(matches!(
+            err,
+            DfuError::Protocol {
+                result_code: ResultCode::ExtError,
+                ext_error: Some(ExtError::HashFailed),
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Crc request failed with ExtError(HashFailed): failed to compute firmware hash"
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        for buf in [
+            &[0x60, 0x03][..],
+            &[0x61, 0x03, 0x01],
+            &[0x60, 0x04, 0x01],
+            &[0x60, 0x03, 0x06],
+            &[0x60, 0x03, 0x0B],
+            &[0x60, 0x03, 0x0B, 0xff],
+        ] {
+            let err = parse_response::<CrcRequest>(buf).unwrap_err();
+            assert!(matches!(err, DfuError::MalformedResponse(_)), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_protocol_version_display() {
+        assert_eq!(ProtocolVersion(1).to_string(), "1");
+    }
+
+    #[test]
+    fn test_hardware_version_display() {
+        let hw = HardwareVersion {
+            part: 0x52840,
+            variant: u32::from_be_bytes(*b"AAD0"),
+            rom_size: 1024 * 1024,
+            ram_size: 256 * 1024,
+            rom_page_size: 4096,
+        };
+        assert_eq!(
+            hw.to_string(),
+            "nRF52840 AAD0, 1024 KiB flash (4 KiB pages), 256 KiB RAM"
+        );
+    }
+}
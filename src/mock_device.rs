@@ -0,0 +1,405 @@
+//! A simulated DFU bootloader for tests.
+//!
+//! Unlike the `MockTransport` of the crate root's tests, which replays scripted responses,
+//! [`MockDevice`] implements enough of the protocol to take part in complete transfers: it keeps
+//! track of the objects it is sent, their offsets and CRCs, and what has been executed. The
+//! connection to it can be dropped after a given number of write requests, so that resuming an
+//! interrupted transfer can be tested without hardware.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+use crate::messages::{ObjectType, OpCode, ResultCode};
+use crate::Transport;
+
+/// The data a device holds for one object type.
+#[derive(Default)]
+struct Objects {
+    /// All data received, including that of the current object.
+    received: Vec<u8>,
+    /// Length of the data that has been executed.
+    executed: usize,
+    /// Size of the current object, as announced when creating it.
+    size: usize,
+}
+
+impl Objects {
+    fn crc(&self) -> u32 {
+        crc32fast::hash(&self.received)
+    }
+}
+
+struct State {
+    mtu: u16,
+    command_max_size: u32,
+    data_max_size: u32,
+    command: Objects,
+    data: Objects,
+    /// The init packet that was executed last. Executing a different one discards the firmware.
+    init_packet: Vec<u8>,
+    /// Object type selected or created last, which `Crc`, `Write` and `Execute` apply to.
+    current: ObjectType,
+    prn: u16,
+    writes_since_receipt: u16,
+    /// Number of write requests the device accepts before the connection drops.
+    writes_until_disconnect: Option<usize>,
+    connected: bool,
+    /// Opcodes of all requests received, in order.
+    requests: Vec<u8>,
+}
+
+/// A simulated bootloader, see the module documentation.
+///
+/// Cloning it yields another handle to the same device.
+#[derive(Clone)]
+pub struct MockDevice(Rc<RefCell<State>>);
+
+impl MockDevice {
+    /// Creates a device reporting an MTU of `mtu` bytes, and accepting command and data objects
+    /// of up to `command_max_size` and `data_max_size` bytes.
+    pub fn new(mtu: u16, command_max_size: u32, data_max_size: u32) -> Self {
+        Self(Rc::new(RefCell::new(State {
+            mtu,
+            command_max_size,
+            data_max_size,
+            command: Objects::default(),
+            data: Objects::default(),
+            init_packet: Vec::new(),
+            current: ObjectType::Command,
+            prn: 0,
+            writes_since_receipt: 0,
+            writes_until_disconnect: None,
+            connected: false,
+            requests: Vec::new(),
+        })))
+    }
+
+    /// Connects to the device. Any previous connection is dropped.
+    pub fn connect(&self) -> Box<dyn Transport> {
+        let mut state = self.0.borrow_mut();
+        state.connected = true;
+        state.writes_until_disconnect = None;
+        Box::new(DeviceTransport {
+            device: self.clone(),
+            responses: VecDeque::new(),
+        })
+    }
+
+    /// Drops the connection once `writes` more write requests have been received. The last of
+    /// them is lost.
+    pub fn disconnect_after_writes(&self, writes: usize) {
+        self.0.borrow_mut().writes_until_disconnect = Some(writes);
+    }
+
+    /// Returns the firmware data that has been executed.
+    pub fn firmware(&self) -> Vec<u8> {
+        let state = self.0.borrow();
+        state.data.received[..state.data.executed].to_vec()
+    }
+
+    /// Returns the init packet that was executed last.
+    pub fn init_packet(&self) -> Vec<u8> {
+        self.0.borrow().init_packet.clone()
+    }
+
+    /// Returns the opcodes of all requests received, in order.
+    pub fn requests(&self) -> Vec<u8> {
+        self.0.borrow().requests.clone()
+    }
+
+    /// Handles the request `frame`, returning the response to send, if any.
+    fn handle(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+        let (opcode, payload) = (frame[0], &frame[1..]);
+        state.requests.push(opcode);
+        let u32_at = |offset: usize| {
+            let bytes = &payload[offset..offset + 4];
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        };
+        let words = |words: &[u32]| -> Vec<u8> {
+            words.iter().flat_map(|word| word.to_le_bytes()).collect()
+        };
+
+        let result = match opcode {
+            op if op == OpCode::ProtocolVersion as u8 => Ok(vec![1]),
+            op if op == OpCode::MtuGet as u8 => Ok(state.mtu.to_le_bytes().to_vec()),
+            op if op == OpCode::Ping as u8 => Ok(vec![payload[0]]),
+            op if op == OpCode::HardwareVersionGet as u8 => {
+                Ok(words(&[0x52840, 0, 1024 * 1024, 256 * 1024, 4096]))
+            }
+            op if op == OpCode::ReceiptNotificationSet as u8 => {
+                state.prn = u16::from_le_bytes([payload[0], payload[1]]);
+                state.writes_since_receipt = 0;
+                Ok(vec![])
+            }
+            op if op == OpCode::Select as u8 => {
+                state.current = object_type(payload[0]);
+                let max_size = match state.current {
+                    ObjectType::Command => state.command_max_size,
+                    ObjectType::Data => state.data_max_size,
+                };
+                let objects = state.objects();
+                Ok(words(&[
+                    max_size,
+                    objects.received.len() as u32,
+                    objects.crc(),
+                ]))
+            }
+            op if op == OpCode::CreateObject as u8 => {
+                state.current = object_type(payload[0]);
+                let size = u32_at(1);
+                let max_size = match state.current {
+                    ObjectType::Command => state.command_max_size,
+                    ObjectType::Data => state.data_max_size,
+                };
+                if size > max_size {
+                    Err(ResultCode::InsufficientResources)
+                } else {
+                    let command = matches!(state.current, ObjectType::Command);
+                    let objects = state.objects();
+                    if command {
+                        // A new init packet replaces the previous one.
+                        objects.received.clear();
+                        objects.executed = 0;
+                    } else {
+                        // Creating an object discards whatever was received since the last execute.
+                        objects.received.truncate(objects.executed);
+                    }
+                    objects.size = size as usize;
+                    state.writes_since_receipt = 0;
+                    Ok(vec![])
+                }
+            }
+            op if op == OpCode::Write as u8 => {
+                if let Some(writes) = &mut state.writes_until_disconnect {
+                    *writes -= 1;
+                    if *writes == 0 {
+                        state.connected = false;
+                        return None;
+                    }
+                }
+                let objects = state.objects();
+                assert!(
+                    objects.received.len() + payload.len() <= objects.executed + objects.size,
+                    "write past the end of the object"
+                );
+                objects.received.extend_from_slice(payload);
+                let (offset, crc) = (objects.received.len() as u32, objects.crc());
+                state.writes_since_receipt += 1;
+                if state.prn == 0 || state.writes_since_receipt < state.prn {
+                    return None;
+                }
+                state.writes_since_receipt = 0;
+                let mut receipt = vec![OpCode::Response as u8, OpCode::Crc as u8, 1];
+                receipt.extend(words(&[offset, crc]));
+                return Some(receipt);
+            }
+            op if op == OpCode::Crc as u8 => {
+                let objects = state.objects();
+                Ok(words(&[objects.received.len() as u32, objects.crc()]))
+            }
+            op if op == OpCode::Execute as u8 => {
+                let current = state.current;
+                let objects = state.objects();
+                let pending = objects.received.len() - objects.executed;
+                if pending != 0 && pending != objects.size {
+                    // The object is incomplete.
+                    Err(ResultCode::OperationNotPermitted)
+                } else {
+                    objects.executed = objects.received.len();
+                    objects.size = 0;
+                    if matches!(current, ObjectType::Command) {
+                        let init_packet = state.command.received.clone();
+                        if init_packet != state.init_packet {
+                            state.data = Objects::default();
+                            state.init_packet = init_packet;
+                        }
+                    }
+                    Ok(vec![])
+                }
+            }
+            op if op == OpCode::Abort as u8 => {
+                // Discards the firmware received so far, but keeps the init packet.
+                state.data = Objects::default();
+                return None;
+            }
+            op => panic!("unexpected opcode 0x{:02x}", op),
+        };
+
+        let mut response = vec![OpCode::Response as u8, opcode];
+        match result {
+            Ok(payload) => {
+                response.push(ResultCode::Success as u8);
+                response.extend(payload);
+            }
+            Err(code) => response.push(code as u8),
+        }
+        Some(response)
+    }
+}
+
+impl State {
+    fn objects(&mut self) -> &mut Objects {
+        match self.current {
+            ObjectType::Command => &mut self.command,
+            ObjectType::Data => &mut self.data,
+        }
+    }
+}
+
+fn object_type(byte: u8) -> ObjectType {
+    match byte {
+        1 => ObjectType::Command,
+        2 => ObjectType::Data,
+        _ => panic!("invalid object type {}", byte),
+    }
+}
+
+/// A connection to a [`MockDevice`].
+struct DeviceTransport {
+    device: MockDevice,
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl DeviceTransport {
+    fn connected(&self) -> bool {
+        self.device.0.borrow().connected
+    }
+}
+
+impl Transport for DeviceTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if !self.connected() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if let Some(response) = self.device.handle(frame) {
+            self.responses.push_back(response);
+        }
+        Ok(())
+    }
+
+    fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        if !self.connected() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let response = self.responses.pop_front().ok_or(io::ErrorKind::TimedOut)?;
+        buf.extend_from_slice(&response);
+        Ok(())
+    }
+
+    fn max_frame_size(&self, mtu: u16) -> usize {
+        usize::from(mtu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_packet, run_with_connection, BootloaderConnection, DfuError, UpdateOptions};
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    /// Opens a new connection to `device`, without retrying the handshake.
+    fn connect(device: &MockDevice) -> BootloaderConnection {
+        let options = UpdateOptions {
+            handshake_retries: 0,
+            ..Default::default()
+        };
+        BootloaderConnection::connect_with_options(device.connect(), &options).unwrap()
+    }
+
+    #[test]
+    fn full_update() {
+        let device = MockDevice::new(20, 256, 64);
+        let bin = image(200);
+        let dat = init_packet::build_init_packet(&bin);
+        let mut conn = connect(&device);
+
+        let options = UpdateOptions {
+            prn: 2,
+            ..Default::default()
+        };
+        let report = run_with_connection(&mut conn, &dat, &bin, &options, |_| {}).unwrap();
+
+        assert_eq!(device.init_packet(), dat);
+        assert_eq!(device.firmware(), bin);
+        assert_eq!(report.bytes_sent, 200);
+        assert_eq!(report.chunks, 4);
+        assert_eq!(report.final_crc, crc32fast::hash(&bin));
+    }
+
+    #[test]
+    fn resume_after_disconnect() {
+        let device = MockDevice::new(20, 256, 64);
+        let bin = image(200);
+        let dat = init_packet::build_init_packet(&bin);
+
+        // Drop the connection in the middle of the third data object.
+        let mut conn = connect(&device);
+        conn.set_resume(true);
+        conn.send_dat(&dat).unwrap();
+        device.disconnect_after_writes(12);
+        let err = conn.send_bin(&bin, &mut |_| {}).unwrap_err();
+        assert!(matches!(err.root(), DfuError::Serial(_)), "{:?}", err);
+        assert_eq!(device.firmware(), bin[..128]);
+
+        let mut conn = connect(&device);
+        conn.set_resume(true);
+        conn.send_dat(&dat).unwrap();
+        let report = conn.send_bin(&bin, &mut |_| {}).unwrap();
+
+        assert_eq!(device.firmware(), bin);
+        // The interrupted object was completed while resuming, only the last one was sent anew.
+        assert_eq!(report.bytes_sent, 8);
+        assert_eq!(report.chunks, 1);
+        assert_eq!(report.final_crc, crc32fast::hash(&bin));
+    }
+
+    #[test]
+    fn reset_first() {
+        let device = MockDevice::new(20, 256, 64);
+        let bin = image(200);
+        let dat = init_packet::build_init_packet(&bin);
+
+        let mut conn = connect(&device);
+        conn.send_dat(&dat).unwrap();
+        device.disconnect_after_writes(12);
+        conn.send_bin(&bin, &mut |_| {}).unwrap_err();
+
+        // The leftover data is discarded before the transfer starts, even though resuming is
+        // enabled.
+        let mut conn = connect(&device);
+        let options = UpdateOptions {
+            reset_first: true,
+            ..Default::default()
+        };
+        let report = run_with_connection(&mut conn, &dat, &bin, &options, |_| {}).unwrap();
+        assert_eq!(device.firmware(), bin);
+        assert_eq!(report.bytes_sent, 200);
+    }
+
+    #[test]
+    fn restart_without_resume() {
+        let device = MockDevice::new(20, 256, 64);
+        let bin = image(200);
+        let dat = init_packet::build_init_packet(&bin);
+
+        let mut conn = connect(&device);
+        conn.send_dat(&dat).unwrap();
+        device.disconnect_after_writes(12);
+        conn.send_bin(&bin, &mut |_| {}).unwrap_err();
+
+        // Without resuming, the data left on the device is discarded and sent again.
+        let mut conn = connect(&device);
+        conn.send_dat(&dat).unwrap();
+        let report = conn.send_bin(&bin, &mut |_| {}).unwrap();
+        assert_eq!(device.firmware(), bin);
+        assert_eq!(report.bytes_sent, 200);
+        assert!(device.requests().contains(&(OpCode::Abort as u8)));
+    }
+}
@@ -1,10 +1,32 @@
+//! SLIP framing, as used by the DFU bootloader over serial ports ([RFC 1055]).
+//!
+//! This is exposed for tools that speak the same framing to other devices:
+//!
+//! ```
+//! use nrfdfu::slip::{decode_frame, encode_frame, SlipDecoder};
+//!
+//! let mut encoded = Vec::new();
+//! encode_frame(&[0x09, 0xC0, 0x01], &mut encoded)?;
+//!
+//! let mut decoded = Vec::new();
+//! decode_frame(&encoded[..], &mut SlipDecoder::new(64), &mut decoded)?;
+//! assert_eq!(decoded, [0x09, 0xC0, 0x01]);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! [RFC 1055]: https://datatracker.ietf.org/doc/html/rfc1055
+
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
+use std::mem;
 
 const END: u8 = 0xC0;
 const ESC: u8 = 0xDB;
 const ESC_END: u8 = 0xDC;
 const ESC_ESC: u8 = 0xDD;
 
+/// Encodes `buf` as a single SLIP frame (including the terminating `END` byte) and writes it to
+/// `writer`, which is typically a `&mut Vec<u8>`.
 pub fn encode_frame(buf: &[u8], mut writer: impl Write) -> io::Result<()> {
     for &byte in buf {
         match byte {
@@ -19,37 +41,134 @@ pub fn encode_frame(buf: &[u8], mut writer: impl Write) -> io::Result<()> {
     Ok(())
 }
 
-pub fn decode_frame(reader: impl Read, buf: &mut Vec<u8>) -> io::Result<()> {
-    let mut bytes = reader.bytes();
-    loop {
-        let encoded_byte = match bytes.next() {
-            Some(byte) => byte,
-            None => return Err(io::ErrorKind::UnexpectedEof.into()),
-        };
-
-        let decoded_byte = match encoded_byte? {
-            ESC => match bytes.next() {
-                None => return Err(io::ErrorKind::UnexpectedEof.into()),
-                Some(Ok(ESC_ESC)) => ESC,
-                Some(Ok(ESC_END)) => END,
-                Some(Ok(invalid)) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("invalid byte following ESC: 0x{:02x}", invalid),
-                    ))
+/// Returns the length of the largest frame whose encoding is guaranteed to fit into `encoded_len`
+/// bytes.
+///
+/// In the worst case, every byte has to be escaped, doubling the size, and the frame terminator
+/// is added on top.
+pub fn max_frame_len(encoded_len: usize) -> usize {
+    encoded_len.saturating_sub(1) / 2
+}
+
+/// Incremental SLIP decoder.
+///
+/// Encoded bytes are fed in with [`push`](Self::push), in pieces of any size, and complete frames
+/// are taken out with [`next_frame`](Self::next_frame). This makes the decoder independent of how
+/// the underlying link delivers data.
+pub struct SlipDecoder {
+    /// Decoded bytes of the frame that is currently being received.
+    frame: Vec<u8>,
+    /// Whether the last byte pushed was an `ESC`.
+    escape: bool,
+    /// Complete frames that have not been taken out yet.
+    frames: VecDeque<Vec<u8>>,
+    max_len: usize,
+}
+
+impl SlipDecoder {
+    /// Creates a decoder that rejects frames longer than `max_len` decoded bytes, so that a device
+    /// which never terminates its frame cannot make us buffer data indefinitely.
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            frame: Vec::new(),
+            escape: false,
+            frames: VecDeque::new(),
+            max_len,
+        }
+    }
+
+    /// Decodes `bytes`, queueing any frames that are completed by them.
+    ///
+    /// On error, the frame that is currently being received and the rest of `bytes` are discarded.
+    pub fn push(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for &byte in bytes {
+            let decoded_byte = if self.escape {
+                self.escape = false;
+                match byte {
+                    ESC_ESC => ESC,
+                    ESC_END => END,
+                    invalid => {
+                        self.frame.clear();
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid byte following ESC: 0x{:02x}", invalid),
+                        ));
+                    }
+                }
+            } else {
+                match byte {
+                    ESC => {
+                        self.escape = true;
+                        continue;
+                    }
+                    END => {
+                        // Some senders also put an END in front of every frame. The empty "frames"
+                        // this produces carry no data, so they are dropped.
+                        if !self.frame.is_empty() {
+                            self.frames.push_back(mem::take(&mut self.frame));
+                        }
+                        continue;
+                    }
+                    other => other,
                 }
-                Some(Err(e)) => return Err(e),
-            },
-            END => return Ok(()),
-            other => other,
-        };
+            };
+
+            if self.frame.len() == self.max_len {
+                self.frame.clear();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "SLIP frame exceeds maximum length of {} bytes",
+                        self.max_len
+                    ),
+                ));
+            }
+            self.frame.push(decoded_byte);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the oldest complete frame that has not been returned yet.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+/// Reads a single SLIP frame from `reader` via `decoder` and appends the decoded bytes to `buf`.
+///
+/// If reading fails (e.g. because it timed out), the part of the frame that was already read is
+/// kept in `decoder`, so a later call continues where this one left off. Fails with
+/// [`io::ErrorKind::InvalidData`] if the decoded frame would be longer than the decoder allows.
+pub fn decode_frame(
+    reader: impl Read,
+    decoder: &mut SlipDecoder,
+    buf: &mut Vec<u8>,
+) -> io::Result<()> {
+    if let Some(frame) = decoder.next_frame() {
+        buf.extend_from_slice(&frame);
+        return Ok(());
+    }
 
-        buf.push(decoded_byte);
+    // Reading byte by byte is intentional: buffering would consume bytes past the end of the
+    // frame, which belong to the next response.
+    #[allow(clippy::unbuffered_bytes)]
+    for byte in reader.bytes() {
+        decoder.push(&[byte?])?;
+        if let Some(frame) = decoder.next_frame() {
+            buf.extend_from_slice(&frame);
+            return Ok(());
+        }
     }
+
+    Err(io::ErrorKind::UnexpectedEof.into())
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
     use super::*;
 
     fn encode(buf: &[u8]) -> Vec<u8> {
@@ -60,7 +179,7 @@ mod tests {
 
     fn decode(mut buf: &[u8]) -> Vec<u8> {
         let mut out = Vec::new();
-        decode_frame(&mut buf, &mut out).unwrap();
+        decode_frame(&mut buf, &mut SlipDecoder::new(16), &mut out).unwrap();
         out
     }
 
@@ -83,4 +202,171 @@ mod tests {
             vec![0, END, ESC, 1]
         );
     }
+
+    #[test]
+    fn test_decode_leading_end() {
+        assert_eq!(
+            decode(&[END, 0x60, 0x09, 0x01, END]),
+            vec![0x60, 0x09, 0x01]
+        );
+        assert_eq!(decode(&[END, END, 1, END]), vec![1]);
+    }
+
+    #[test]
+    fn test_max_frame_len() {
+        for encoded_len in vec![3, 4, 20, 21, 64, 65, 131, 1024, usize::from(u16::MAX)] {
+            let max_len = max_frame_len(encoded_len);
+            assert!(max_len > 0);
+
+            // Worst case: every byte needs escaping.
+            for &byte in &[END, ESC] {
+                let frame = vec![byte; max_len];
+                assert!(
+                    encode(&frame).len() <= encoded_len,
+                    "frame of {} bytes exceeds {} bytes after encoding",
+                    max_len,
+                    encoded_len
+                );
+            }
+
+            // The bound is tight: one more byte can exceed the limit.
+            let frame = vec![END; max_len + 1];
+            assert!(encode(&frame).len() > encoded_len);
+        }
+
+        // Nothing fits into tiny buffers, but that must not underflow.
+        assert_eq!(max_frame_len(0), 0);
+        assert_eq!(max_frame_len(1), 0);
+        assert_eq!(max_frame_len(2), 0);
+    }
+
+    #[test]
+    fn test_decode_max_len() {
+        let mut out = Vec::new();
+        decode_frame(&mut &[1; 16][..], &mut SlipDecoder::new(16), &mut out).unwrap_err();
+        // Exactly `max_len` bytes are fine.
+        out.clear();
+        let mut decoder = SlipDecoder::new(3);
+        decode_frame(&mut &[1, 2, ESC, ESC_END, END][..], &mut decoder, &mut out).unwrap();
+        assert_eq!(out, vec![1, 2, END]);
+    }
+
+    #[test]
+    fn test_decode_unterminated() {
+        // A device that never sends a terminator must not make us read forever.
+        let mut out = Vec::new();
+        let err = decode_frame(io::repeat(0), &mut SlipDecoder::new(64), &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_decode_split_reads() {
+        // The first read ends right after an `ESC` (e.g. because it timed out), and the escaped
+        // byte only arrives with the next one.
+        let mut decoder = SlipDecoder::new(16);
+        let mut out = Vec::new();
+        let err = decode_frame(&mut &[1, ESC][..], &mut decoder, &mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(out.is_empty());
+
+        decode_frame(&mut &[ESC_END, 2, END][..], &mut decoder, &mut out).unwrap();
+        assert_eq!(out, vec![1, END, 2]);
+    }
+
+    #[test]
+    fn test_decoder_split_input() {
+        let mut decoder = SlipDecoder::new(16);
+        decoder.push(&[1, ESC]).unwrap();
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&[ESC_ESC, 2]).unwrap();
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&[END]).unwrap();
+        assert_eq!(decoder.next_frame(), Some(vec![1, ESC, 2]));
+        assert_eq!(decoder.next_frame(), None);
+    }
+
+    #[test]
+    fn test_decoder_multiple_frames() {
+        let mut decoder = SlipDecoder::new(16);
+        decoder
+            .push(&[1, END, ESC, ESC_END, END, 2, 3, END, 4])
+            .unwrap();
+        assert_eq!(decoder.next_frame(), Some(vec![1]));
+        assert_eq!(decoder.next_frame(), Some(vec![END]));
+        assert_eq!(decoder.next_frame(), Some(vec![2, 3]));
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&[END]).unwrap();
+        assert_eq!(decoder.next_frame(), Some(vec![4]));
+    }
+
+    #[test]
+    fn test_roundtrip_escapes() {
+        // Both bytes that need escaping, alone, repeated, and followed by the byte that would
+        // complete their escape sequence.
+        for frame in &[
+            vec![END],
+            vec![ESC],
+            vec![END, END],
+            vec![ESC, ESC],
+            vec![END, ESC_END],
+            vec![ESC, ESC_ESC],
+            vec![ESC, END, ESC_ESC, ESC_END],
+        ] {
+            assert_eq!(&decode(&encode(frame)), frame);
+        }
+    }
+
+    /// Bytes with the special bytes of SLIP heavily overrepresented, so that escaping is
+    /// exercised by almost every input.
+    fn slip_byte() -> impl Strategy<Value = u8> {
+        prop_oneof![
+            Just(END),
+            Just(ESC),
+            Just(ESC_END),
+            Just(ESC_ESC),
+            any::<u8>()
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(frame in vec(slip_byte(), 1..256)) {
+            let mut decoded = Vec::new();
+            let mut decoder = SlipDecoder::new(frame.len());
+            decode_frame(&encode(&frame)[..], &mut decoder, &mut decoded).unwrap();
+            prop_assert_eq!(decoded, frame);
+        }
+
+        #[test]
+        fn prop_roundtrip_split(
+            frames in vec(vec(slip_byte(), 1..64), 1..8),
+            split in any::<prop::sample::Index>(),
+        ) {
+            // Decoding must not depend on where the encoded data is split up.
+            let mut encoded = Vec::new();
+            for frame in &frames {
+                encode_frame(frame, &mut encoded).unwrap();
+            }
+            let (first, second) = encoded.split_at(split.index(encoded.len()));
+
+            let mut decoder = SlipDecoder::new(64);
+            decoder.push(first).unwrap();
+            decoder.push(second).unwrap();
+            let decoded = std::iter::from_fn(|| decoder.next_frame()).collect::<Vec<_>>();
+            prop_assert_eq!(decoded, frames);
+        }
+    }
+
+    #[test]
+    fn test_decoder_invalid_escape() {
+        let mut decoder = SlipDecoder::new(16);
+        let err = decoder.push(&[1, ESC, 2, END]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decoder.next_frame(), None);
+
+        // The decoder recovers with the next frame.
+        decoder.push(&[3, END]).unwrap();
+        assert_eq!(decoder.next_frame(), Some(vec![3]));
+    }
 }
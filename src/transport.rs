@@ -0,0 +1,222 @@
+//! Transports carrying request and response frames between us and the bootloader.
+
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+use crate::slip::{self, SlipDecoder};
+
+/// Upper bound for the size of a response frame.
+///
+/// The largest response defined by the protocol is only a few dozen bytes long, so anything
+/// longer than this means the device is misbehaving.
+pub(crate) const MAX_RESPONSE_LEN: usize = 256;
+
+/// A link to a DFU bootloader, which can exchange whole protocol frames.
+///
+/// A frame consists of the opcode byte followed by the request or response payload. Any framing
+/// or encoding required by the underlying link is up to the implementation.
+pub trait Transport {
+    /// Sends a single request frame to the device.
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Waits for a single response frame from the device and appends it to `buf`.
+    fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Returns the largest request frame (including the opcode) that can be sent, given the
+    /// `mtu` reported by the device.
+    fn max_frame_size(&self, mtu: u16) -> usize;
+
+    /// Returns how long [`recv_frame`](Self::recv_frame) waits for a response, or `None` if the
+    /// transport has no adjustable timeout (the default).
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Changes how long [`recv_frame`](Self::recv_frame) waits for a response. Ignored by
+    /// transports without an adjustable timeout.
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        let _ = timeout;
+        Ok(())
+    }
+}
+
+/// Transport over a serial port (USB CDC ACM or UART), using SLIP framing.
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+    buf: Vec<u8>,
+    /// Kept across reads, so that a frame split by a timed out read is not corrupted.
+    decoder: SlipDecoder,
+}
+
+impl SerialTransport {
+    pub fn new(mut port: Box<dyn SerialPort>) -> io::Result<Self> {
+        // On Windows, this is required, otherwise communication fails with timeouts
+        // (or just hangs forever).
+        port.write_data_terminal_ready(true)?;
+
+        Ok(Self {
+            port,
+            buf: Vec::new(),
+            decoder: SlipDecoder::new(MAX_RESPONSE_LEN),
+        })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        // Go through an intermediate buffer to avoid writing every byte individually.
+        self.buf.clear();
+        slip::encode_frame(frame, &mut self.buf)?;
+        self.port.write_all(&self.buf)?;
+        self.port.flush()
+    }
+
+    fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        slip::decode_frame(&mut self.port, &mut self.decoder, buf)
+    }
+
+    fn max_frame_size(&self, mtu: u16) -> usize {
+        // The MTU applies to the SLIP-encoded frame.
+        slip::max_frame_len(usize::from(mtu))
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Some(self.port.timeout())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_timeout(timeout)?;
+        Ok(())
+    }
+}
+
+/// Transport over a TCP connection to a serial-to-TCP bridge, using SLIP framing.
+///
+/// The bridge is expected to pass the raw bytes of the device's serial port through unchanged, as
+/// e.g. `ser2net` in raw mode does. This allows flashing a device attached to another machine.
+pub struct TcpTransport {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    /// Kept across reads, so that a frame split by a timed out read is not corrupted.
+    decoder: SlipDecoder,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    /// Connects to the bridge at `addr`, e.g. `"rig-3.local:4000"`. `timeout` applies to
+    /// establishing the connection and to every read and write on it.
+    pub fn connect(addr: impl ToSocketAddrs, timeout: Duration) -> io::Result<Self> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Self::from_stream(stream, timeout),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+        }))
+    }
+
+    /// Like [`connect`](Self::connect), but uses the already established connection `stream`.
+    pub fn from_stream(stream: TcpStream, timeout: Duration) -> io::Result<Self> {
+        // Requests are small and always wait for a response, so don't delay sending them.
+        stream.set_nodelay(true)?;
+        let mut this = Self {
+            stream,
+            buf: Vec::new(),
+            decoder: SlipDecoder::new(MAX_RESPONSE_LEN),
+            timeout,
+        };
+        this.set_timeout(timeout)?;
+        Ok(this)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.buf.clear();
+        slip::encode_frame(frame, &mut self.buf)?;
+        self.stream.write_all(&self.buf).map_err(timed_out)?;
+        self.stream.flush()
+    }
+
+    fn recv_frame(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        slip::decode_frame(&mut self.stream, &mut self.decoder, buf).map_err(timed_out)
+    }
+
+    fn max_frame_size(&self, mtu: u16) -> usize {
+        // The bridge forwards the SLIP-encoded frame to the serial port as is.
+        slip::max_frame_len(usize::from(mtu))
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Some(self.timeout)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.set_write_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+/// Reports a socket timeout as [`io::ErrorKind::TimedOut`], like a serial port does.
+///
+/// On Unix, a socket operation that exceeds its timeout fails with `WouldBlock` instead.
+fn timed_out(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::WouldBlock {
+        io::ErrorKind::TimedOut.into()
+    } else {
+        e
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_tcp_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bridge = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).unwrap();
+            // Respond in two parts, to check that the frame is reassembled.
+            stream.write_all(&[0x60, 0x09]).unwrap();
+            stream.write_all(&[0x01, 0xDB, 0xDC, 0xC0]).unwrap();
+            request
+        });
+
+        let mut transport = TcpTransport::connect(addr, Duration::from_secs(5)).unwrap();
+        transport.send_frame(&[0x09, 0xC0]).unwrap();
+        let mut response = Vec::new();
+        transport.recv_frame(&mut response).unwrap();
+        assert_eq!(response, [0x60, 0x09, 0x01, 0xC0]);
+        assert_eq!(bridge.join().unwrap(), [0x09, 0xDB, 0xDC, 0xC0]);
+    }
+
+    #[test]
+    fn test_tcp_transport_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut transport =
+            TcpTransport::connect(listener.local_addr().unwrap(), Duration::from_secs(5)).unwrap();
+        // Keep the connection open without ever responding.
+        let _stream = listener.accept().unwrap();
+
+        transport.set_timeout(Duration::from_millis(50)).unwrap();
+        assert_eq!(transport.timeout(), Some(Duration::from_millis(50)));
+        let err = transport.recv_frame(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
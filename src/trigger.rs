@@ -0,0 +1,143 @@
+//! Resetting a device into its bootloader via Nordic's USB DFU trigger interface.
+//!
+//! Applications built with the nRF5 SDK's `nrf_dfu_trigger_usb` library (or the equivalent nRF
+//! Connect SDK module) expose a vendor-specific USB interface. A DFU_DETACH request sent to that
+//! interface makes the device reset into the bootloader.
+
+use std::time::Duration;
+
+use rusb::{Device, GlobalContext};
+
+use crate::{DfuError, Result};
+
+/// Class, subclass and protocol of the DFU trigger interface.
+const TRIGGER_INTERFACE: (u8, u8, u8) = (0xFF, 0x01, 0x01);
+
+/// Class request asking the device to detach and enter the bootloader.
+const DFU_DETACH_REQUEST: u8 = 0x00;
+
+/// Host-to-device class request, addressed to an interface.
+const REQUEST_TYPE_CLASS_INTERFACE_OUT: u8 = 0x21;
+
+const USB_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Resets the USB device with the given `vid` and `pid` into its bootloader.
+///
+/// If `serial_number` is given, only the device with that USB serial number is considered.
+/// Otherwise, exactly one device with a DFU trigger interface must match.
+///
+/// This only sends the request; it is up to the caller to wait until the bootloader shows up.
+pub fn trigger_dfu(vid: u16, pid: u16, serial_number: Option<&str>) -> Result<()> {
+    let mut candidates = Vec::new();
+    let mut inaccessible = None;
+    for device in rusb::devices().map_err(usb_error)?.iter() {
+        let descriptor = device.device_descriptor().map_err(usb_error)?;
+        if descriptor.vendor_id() != vid || descriptor.product_id() != pid {
+            continue;
+        }
+
+        let interface = match find_trigger_interface(&device)? {
+            Some(interface) => interface,
+            None => {
+                log::debug!("{:?} has no DFU trigger interface", device);
+                continue;
+            }
+        };
+
+        if let Some(serial_number) = serial_number {
+            // Other devices with the same VID and PID may not be accessible to us (eg. due to
+            // permissions), which must not prevent finding the one we're looking for.
+            let actual = match device
+                .open()
+                .and_then(|handle| handle.read_serial_number_string_ascii(&descriptor))
+            {
+                Ok(actual) => actual,
+                Err(e) => {
+                    log::debug!(
+                        "skipping {:?}, failed to read its serial number: {}",
+                        device,
+                        e
+                    );
+                    inaccessible = Some(e);
+                    continue;
+                }
+            };
+            if actual != serial_number {
+                continue;
+            }
+        }
+
+        candidates.push((device, interface));
+    }
+
+    let (device, interface) = match candidates.len() {
+        0 => {
+            let mut message = format!(
+                "no USB device with a DFU trigger interface found (VID 0x{:04x}, PID 0x{:04x})",
+                vid, pid
+            );
+            if let Some(e) = inaccessible {
+                message += &format!(
+                    "; the serial number of some devices could not be read: {}",
+                    e
+                );
+            }
+            return Err(DfuError::Trigger(message));
+        }
+        1 => candidates.pop().unwrap(),
+        _ => {
+            return Err(DfuError::Trigger(
+                "multiple USB devices with a DFU trigger interface found, select one with its \
+                 serial number"
+                    .to_string(),
+            ))
+        }
+    };
+
+    log::debug!(
+        "sending DFU_DETACH to interface {} of {:?}",
+        interface,
+        device
+    );
+    let handle = device.open().map_err(usb_error)?;
+    handle.claim_interface(interface).map_err(usb_error)?;
+    let result = handle.write_control(
+        REQUEST_TYPE_CLASS_INTERFACE_OUT,
+        DFU_DETACH_REQUEST,
+        0,
+        u16::from(interface),
+        &[],
+        USB_TIMEOUT,
+    );
+    match result {
+        Ok(_) => Ok(()),
+        // The device may reset before it has acknowledged the request.
+        Err(e @ (rusb::Error::NoDevice | rusb::Error::Io | rusb::Error::Pipe)) => {
+            log::debug!("device reset during DFU_DETACH request: {}", e);
+            Ok(())
+        }
+        Err(e) => Err(usb_error(e)),
+    }
+}
+
+/// Returns the number of the DFU trigger interface of `device`, if it has one.
+fn find_trigger_interface(device: &Device<GlobalContext>) -> Result<Option<u8>> {
+    let config = device.active_config_descriptor().map_err(usb_error)?;
+    for interface in config.interfaces() {
+        for descriptor in interface.descriptors() {
+            let class = (
+                descriptor.class_code(),
+                descriptor.sub_class_code(),
+                descriptor.protocol_code(),
+            );
+            if class == TRIGGER_INTERFACE {
+                return Ok(Some(descriptor.interface_number()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn usb_error(e: rusb::Error) -> DfuError {
+    DfuError::Trigger(e.to_string())
+}
@@ -1,30 +1,532 @@
+use std::convert::TryFrom;
+use std::fmt;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read, Seek};
+use flate2::read::GzDecoder;
 use serde_json::from_str;
+use zip::result::ZipError;
 use zip::ZipArchive;
-use crate::OuterManifest;
+use crate::{init_packet, DfuError, InitPacketData, OuterManifest};
 
-pub fn read_zip_file(path: &str) -> crate::Result<(Vec<u8>, Vec<u8>)> {
-    let reader = fs::File::open(path)?;
+/// The kind of firmware contained in an image of a DFU package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    SoftdeviceBootloader,
+    Softdevice,
+    Bootloader,
+    Application,
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ImageKind::SoftdeviceBootloader => "softdevice_bootloader",
+            ImageKind::Softdevice => "softdevice",
+            ImageKind::Bootloader => "bootloader",
+            ImageKind::Application => "application",
+        })
+    }
+}
+
+/// An init packet and the firmware image it describes.
+///
+/// The firmware image itself is only read from the package when needed, see
+/// [`DfuPackage::open_bin`].
+pub struct FirmwareImage {
+    pub kind: ImageKind,
+    /// Name of the init packet file in the package.
+    pub dat_file: String,
+    pub dat: Vec<u8>,
+    /// Name of the firmware image file in the package.
+    pub bin_file: String,
+    /// Size of the firmware image in bytes, after decompression if `bin_file` is gzip-compressed.
+    pub bin_len: usize,
+    /// Metadata declared for this image in the manifest, if any.
+    pub init_packet_data: Option<InitPacketData>,
+}
+
+/// Where the firmware images of a [`DfuPackage`] are read from.
+enum Source<R> {
+    /// The .zip file containing the images.
+    Archive(ZipArchive<R>),
+    /// Loose files, see [`read_files`].
+    Files,
+    /// The single firmware image, held in memory (see [`read_bytes`]).
+    Memory(Vec<u8>),
+}
+
+/// A validated DFU package.
+pub struct DfuPackage<R> {
+    source: Source<R>,
+    /// Name of the manifest in the package, or `None` for loose files.
+    pub manifest_file: Option<String>,
+    /// The `dfu_version` declared by the manifest, if any.
+    pub dfu_version: Option<f64>,
+    /// The images in the order in which they have to be flashed.
+    pub images: Vec<FirmwareImage>,
+}
+
+impl<R: Read + Seek> DfuPackage<R> {
+    /// Opens the firmware image of `images[index]` for reading, decompressing it if needed.
+    pub fn open_bin(&mut self, index: usize) -> crate::Result<Box<dyn Read + Send + '_>> {
+        let bin_file = &self.images[index].bin_file;
+        let reader: Box<dyn Read + Send + '_> = match &mut self.source {
+            Source::Archive(archive) => {
+                // A `ZipFile` reads through a `&mut dyn Read`, which is not `Send`. Images are
+                // small, so the (possibly still gzip-compressed) file is read into memory instead,
+                // which allows flashing from async tasks on multi-threaded runtimes.
+                let mut contents = Vec::new();
+                archive
+                    .by_name(bin_file)?
+                    .read_to_end(&mut contents)
+                    .map_err(ZipError::Io)?;
+                Box::new(io::Cursor::new(contents))
+            }
+            Source::Files => Box::new(fs::File::open(bin_file).map_err(ZipError::Io)?),
+            Source::Memory(bin) => return Ok(Box::new(bin.as_slice())),
+        };
+        // Unlike `decompress`, this keeps the reader `Send`.
+        if is_gzip(bin_file) {
+            Ok(Box::new(GzDecoder::new(reader)))
+        } else {
+            Ok(reader)
+        }
+    }
+}
+
+/// Reads the DFU package at `path`.
+pub fn read_zip_file(
+    path: &str,
+    strict_dfu_version: bool,
+    manifest_name: &str,
+) -> crate::Result<DfuPackage<fs::File>> {
+    let reader = fs::File::open(path).map_err(ZipError::Io)?;
+    read_zip(reader, strict_dfu_version, manifest_name)
+}
+
+/// Reads a DFU package provided by `reader`.
+///
+/// The images are returned in the order in which they have to be flashed: SoftDevice and
+/// bootloader first, application last. Every image is checked against the size and hash declared
+/// by its init packet, but not kept in memory.
+///
+/// If the manifest declares a `dfu_version`, the package is rejected if `strict_dfu_version` is
+/// set, and a warning is logged otherwise.
+///
+/// Init packets and firmware images whose name ends in `.gz` are decompressed transparently.
+///
+/// The manifest is read from `manifest_name`, or if the package contains no such file, from the
+/// first file (in alphabetical order) whose name ends in `manifest.json`. The files it references
+/// are looked up relative to the directory containing it.
+pub fn read_zip<R: Read + Seek>(
+    reader: R,
+    strict_dfu_version: bool,
+    manifest_name: &str,
+) -> crate::Result<DfuPackage<R>> {
     let mut archive = ZipArchive::new(reader)?;
-    let application = {
-        let mut file = archive.by_name("manifest.json")?;
+    let manifest_name = find_manifest(&archive, manifest_name)?;
+    let dir = &manifest_name[..manifest_name.rfind('/').map_or(0, |i| i + 1)];
+    let manifest = {
+        let mut file = archive.by_name(&manifest_name)?;
         let mut manifest_string = String::new();
-        file.read_to_string(&mut manifest_string)?;
+        file.read_to_string(&mut manifest_string)
+            .map_err(ZipError::Io)?;
         let outer = from_str::<OuterManifest>(&manifest_string)?;
-        outer.manifest.application
-    };
-    let dat_file = {
-        let mut file = archive.by_name(&application.dat_file)?;
-        let mut dat_vec = Vec::new();
-        file.read_to_end(&mut dat_vec)?;
-        dat_vec
-    };
-    let bin_file = {
-        let mut file = archive.by_name(&application.bin_file)?;
-        let mut bin_vec = Vec::new();
-        file.read_to_end(&mut bin_vec)?;
-        bin_vec
+        outer.manifest
     };
-    Ok((dat_file, bin_file))
-}
\ No newline at end of file
+    log::debug!("manifest:\n{}", manifest.describe());
+
+    if let Some(version) = manifest.dfu_version {
+        let msg = format!(
+            "manifest declares dfu_version {}, which this version of nrfdfu has not been \
+             validated against",
+            version
+        );
+        if strict_dfu_version {
+            return Err(DfuError::InvalidPackage(msg));
+        }
+        log::warn!("{}", msg);
+    }
+
+    let mut images = Vec::new();
+    for (kind, image) in manifest.images() {
+        let dat_file = format!("{}{}", dir, image.dat_file);
+        let bin_file = format!("{}{}", dir, image.bin_file);
+        let dat = read_file(&mut archive, &dat_file)?;
+        check_present(&archive, &bin_file)?;
+        let bin_len = {
+            let bin = archive.by_name(&bin_file)?;
+            let size = bin.size();
+            decompressed_len(&bin_file, bin, size)?
+        };
+        let bin_len = usize::try_from(bin_len)
+            .map_err(|_| DfuError::InvalidPackage(format!("{} image is too large", kind)))?;
+        check_not_empty(kind, &dat_file, &dat, &bin_file, bin_len)?;
+        let bin = decompress(&bin_file, archive.by_name(&bin_file)?);
+        init_packet::verify_image(&dat, bin).map_err(|e| match e {
+            DfuError::InitPacketMismatch(msg) => {
+                DfuError::InitPacketMismatch(format!("{} image: {}", kind, msg))
+            }
+            e => e,
+        })?;
+        images.push(FirmwareImage {
+            kind,
+            dat_file,
+            dat,
+            bin_file,
+            bin_len,
+            init_packet_data: image.init_packet_data.clone(),
+        });
+    }
+
+    if images.is_empty() {
+        return Err(DfuError::InvalidPackage(
+            "manifest does not list any firmware images".to_string(),
+        ));
+    }
+
+    let dfu_version = manifest.dfu_version;
+    Ok(DfuPackage {
+        source: Source::Archive(archive),
+        manifest_file: Some(manifest_name),
+        dfu_version,
+        images,
+    })
+}
+
+/// Reads a single image from the loose init packet at `dat_path` and firmware image at
+/// `bin_path`, as produced by a build before it is packaged into a .zip file.
+///
+/// The kind of the image is taken from the init packet, and the firmware image is checked against
+/// it just like in [`read_zip`]. Files whose name ends in `.gz` are decompressed transparently.
+pub fn read_files(dat_path: &str, bin_path: &str) -> crate::Result<DfuPackage<fs::File>> {
+    let mut dat = Vec::new();
+    decompress(dat_path, fs::File::open(dat_path).map_err(ZipError::Io)?)
+        .read_to_end(&mut dat)
+        .map_err(ZipError::Io)?;
+    if dat.is_empty() {
+        return Err(DfuError::InvalidPackage(format!("{} is empty", dat_path)));
+    }
+    let kind = init_packet::image_kind(&dat)?;
+    let bin = fs::File::open(bin_path).map_err(ZipError::Io)?;
+    let size = bin.metadata().map_err(ZipError::Io)?.len();
+    let bin_len = usize::try_from(decompressed_len(bin_path, bin, size)?)
+        .map_err(|_| DfuError::InvalidPackage(format!("{} image is too large", kind)))?;
+    check_not_empty(kind, dat_path, &dat, bin_path, bin_len)?;
+    let bin = fs::File::open(bin_path).map_err(ZipError::Io)?;
+    init_packet::verify_image(&dat, decompress(bin_path, bin))?;
+
+    Ok(DfuPackage {
+        source: Source::Files,
+        manifest_file: None,
+        dfu_version: None,
+        images: vec![FirmwareImage {
+            kind,
+            dat_file: dat_path.to_string(),
+            dat,
+            bin_file: bin_path.to_string(),
+            bin_len,
+            init_packet_data: None,
+        }],
+    })
+}
+
+/// Like [`read_files`], but takes the init packet `dat` and firmware image `bin` from memory.
+pub fn read_bytes(dat: &[u8], bin: &[u8]) -> crate::Result<DfuPackage<io::Cursor<Vec<u8>>>> {
+    let (dat_file, bin_file) = ("<init packet>", "<firmware image>");
+    if dat.is_empty() {
+        return Err(DfuError::InvalidPackage(format!("{} is empty", dat_file)));
+    }
+    let kind = init_packet::image_kind(dat)?;
+    check_not_empty(kind, dat_file, dat, bin_file, bin.len())?;
+    init_packet::verify_image(dat, bin)?;
+
+    Ok(DfuPackage {
+        source: Source::Memory(bin.to_vec()),
+        manifest_file: None,
+        dfu_version: None,
+        images: vec![FirmwareImage {
+            kind,
+            dat_file: dat_file.to_string(),
+            dat: dat.to_vec(),
+            bin_file: bin_file.to_string(),
+            bin_len: bin.len(),
+            init_packet_data: None,
+        }],
+    })
+}
+
+/// Rejects empty init packets and firmware images, which would make an update "succeed" without
+/// flashing anything.
+fn check_not_empty(
+    kind: ImageKind,
+    dat_file: &str,
+    dat: &[u8],
+    bin_file: &str,
+    bin_len: usize,
+) -> crate::Result<()> {
+    for (name, len) in &[(dat_file, dat.len()), (bin_file, bin_len)] {
+        if *len == 0 {
+            return Err(DfuError::InvalidPackage(format!(
+                "{} of the {} image is empty",
+                name, kind
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn read_file<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> crate::Result<Vec<u8>> {
+    check_present(archive, name)?;
+    let file = archive.by_name(name)?;
+    let mut contents = Vec::new();
+    decompress(name, file)
+        .read_to_end(&mut contents)
+        .map_err(ZipError::Io)?;
+    Ok(contents)
+}
+
+/// Returns whether the file `name` is gzip-compressed, going by its extension.
+fn is_gzip(name: &str) -> bool {
+    name.ends_with(".gz")
+}
+
+/// Wraps `reader` in a gzip decoder if the file `name` is compressed.
+fn decompress<'a>(name: &str, reader: impl Read + 'a) -> Box<dyn Read + 'a> {
+    if is_gzip(name) {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    }
+}
+
+/// Returns the size of the file `name` after decompression, given its stored size `len`.
+///
+/// Gzip only records the decompressed size modulo 4 GiB, so compressed files are decompressed
+/// once to determine it.
+fn decompressed_len(name: &str, reader: impl Read, len: u64) -> crate::Result<u64> {
+    if !is_gzip(name) {
+        return Ok(len);
+    }
+    let len = io::copy(&mut GzDecoder::new(reader), &mut io::sink()).map_err(ZipError::Io)?;
+    Ok(len)
+}
+
+/// Returns the name of the manifest in `archive`: `name` if present, otherwise the first file
+/// whose name ends in `manifest.json`.
+fn find_manifest<R: Read + Seek>(archive: &ZipArchive<R>, name: &str) -> crate::Result<String> {
+    let mut entries = archive.file_names().collect::<Vec<_>>();
+    if entries.contains(&name) {
+        return Ok(name.to_string());
+    }
+    entries.sort_unstable();
+    let found = entries
+        .iter()
+        .find(|entry| entry.ends_with("manifest.json"));
+    match found {
+        Some(entry) => {
+            log::debug!("{} not found, using {} as manifest", name, entry);
+            Ok(entry.to_string())
+        }
+        None => Err(DfuError::InvalidPackage(format!(
+            "no manifest found in the DFU package (expected '{}', found: {})",
+            name,
+            entries.join(", ")
+        ))),
+    }
+}
+
+fn check_present<R: Read + Seek>(archive: &ZipArchive<R>, name: &str) -> crate::Result<()> {
+    if !archive.file_names().any(|entry| entry == name) {
+        let mut entries = archive.file_names().collect::<Vec<_>>();
+        entries.sort_unstable();
+        let entries = entries.join(", ");
+        return Err(DfuError::InvalidPackage(format!(
+            "manifest references '{}' but it is not present in the DFU package (found: {})",
+            name, entries
+        )));
+    }
+    Ok(())
+}
+
+/// Returns a ZIP archive containing `files`, given as name and contents, for use as a DFU package
+/// in tests.
+#[cfg(test)]
+pub(crate) fn zip_package(files: &[(&str, &[u8])]) -> io::Cursor<Vec<u8>> {
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    for (name, contents) in files {
+        writer.start_file(*name, FileOptions::default()).unwrap();
+        writer.write_all(contents).unwrap();
+    }
+    let mut zip = writer.finish().unwrap();
+    zip.set_position(0);
+    zip
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    #[test]
+    fn missing_file() {
+        let zip = zip_package(&[
+            (
+                "manifest.json",
+                br#"{"manifest":{"application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#,
+            ),
+            ("app.dat", &[]),
+        ]);
+
+        let err = read_zip(zip, false, "manifest.json").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid DFU package: manifest references 'app.bin' but it is not present in the DFU \
+             package (found: app.dat, manifest.json)"
+        );
+    }
+
+    #[test]
+    fn empty_image() {
+        let zip = zip_package(&[
+            (
+                "manifest.json",
+                br#"{"manifest":{"application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#,
+            ),
+            ("app.dat", &init_packet::build_init_packet(&[])),
+            ("app.bin", &[]),
+        ]);
+
+        let err = read_zip(zip, false, "manifest.json").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid DFU package: app.bin of the application image is empty"
+        );
+    }
+
+    #[test]
+    fn gzip_image() {
+        let bin = [1, 2, 3, 4, 5];
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&bin).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let zip = zip_package(&[
+            (
+                "manifest.json",
+                br#"{"manifest":{"application":{"bin_file":"app.bin.gz","dat_file":"app.dat"}}}"#,
+            ),
+            ("app.dat", &init_packet::build_init_packet(&bin)),
+            ("app.bin.gz", &compressed),
+        ]);
+
+        let mut package = read_zip(zip, false, "manifest.json").unwrap();
+        assert_eq!(package.images[0].bin_len, bin.len());
+        let mut contents = Vec::new();
+        package
+            .open_bin(0)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, bin);
+    }
+
+    #[test]
+    fn manifest_name() {
+        let bin = [1, 2, 3, 4, 5];
+        let zip = zip_package(&[
+            (
+                "pkg/dfu_manifest.json",
+                br#"{"manifest":{"application":{"bin_file":"app.bin","dat_file":"app.dat"}}}"#,
+            ),
+            ("pkg/app.dat", &init_packet::build_init_packet(&bin)),
+            ("pkg/app.bin", &bin),
+        ]);
+
+        for name in &["pkg/dfu_manifest.json", "manifest.json"] {
+            let package = read_zip(zip.clone(), false, name).unwrap();
+            assert_eq!(package.images[0].dat_file, "pkg/app.dat");
+            assert_eq!(package.images[0].bin_file, "pkg/app.bin");
+        }
+
+        let zip = zip_package(&[("app.bin", &[])]);
+        let err = read_zip(zip, false, "manifest.json").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid DFU package: no manifest found in the DFU package (expected 'manifest.json', \
+             found: app.bin)"
+        );
+    }
+
+    #[test]
+    fn loose_files() {
+        let bin = [1, 2, 3, 4, 5];
+        let dir = std::env::temp_dir().join(format!("nrfdfu-loose-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dat_path = dir.join("app.dat");
+        let bin_path = dir.join("app.bin");
+        fs::write(&dat_path, init_packet::build_init_packet(&bin)).unwrap();
+        fs::write(&bin_path, bin).unwrap();
+
+        let mut package =
+            read_files(dat_path.to_str().unwrap(), bin_path.to_str().unwrap()).unwrap();
+        assert_eq!(package.images.len(), 1);
+        assert_eq!(package.images[0].kind, ImageKind::Application);
+        assert_eq!(package.images[0].bin_len, bin.len());
+        let mut contents = Vec::new();
+        package
+            .open_bin(0)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, bin);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn in_memory() {
+        let bin = [1, 2, 3, 4, 5];
+        let dat = init_packet::build_init_packet(&bin);
+
+        let mut package = read_bytes(&dat, &bin).unwrap();
+        assert_eq!(package.images[0].kind, ImageKind::Application);
+        assert_eq!(package.images[0].bin_len, bin.len());
+        let mut contents = Vec::new();
+        package
+            .open_bin(0)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, bin);
+
+        let err = read_bytes(&dat, &bin[..4]).err().unwrap();
+        assert!(matches!(err, DfuError::InitPacketMismatch(_)));
+    }
+
+    #[test]
+    fn strict_dfu_version() {
+        let zip = zip_package(&[("manifest.json", br#"{"manifest":{"dfu_version":0.5}}"#)]);
+
+        let err = read_zip(zip.clone(), true, "manifest.json").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid DFU package: manifest declares dfu_version 0.5, which this version of nrfdfu \
+             has not been validated against"
+        );
+
+        // Without `strict_dfu_version`, reading continues (and fails since there are no images).
+        let err = read_zip(zip, false, "manifest.json").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid DFU package: manifest does not list any firmware images"
+        );
+    }
+}